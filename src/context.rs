@@ -0,0 +1,498 @@
+//! Parsing and applying of context diffs, as produced by `diff -c`
+use crate::parse::Error;
+use crate::patch::{ApplyError, ApplyResult, Hunk, HunkLine, Patch, UnifiedPatch};
+
+/// A line in one side of a context diff hunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContextLine {
+    /// A line that is the same in both files (`  `)
+    Unchanged(Vec<u8>),
+
+    /// A line that only exists in the original file (`- `)
+    Removed(Vec<u8>),
+
+    /// A line that only exists in the modified file (`+ `)
+    Added(Vec<u8>),
+
+    /// A line that was changed (`! `)
+    Changed(Vec<u8>),
+}
+
+impl ContextLine {
+    /// Parse a line from a context diff hunk
+    ///
+    /// # Returns
+    /// The parsed line, or `None` if the line does not start with a change marker
+    pub fn parse_line(line: &[u8]) -> Option<Self> {
+        if line == b"\n" {
+            return Some(Self::Unchanged(line.to_vec()));
+        }
+        let contents = line.get(2..)?.to_vec();
+        match line.get(..2)? {
+            b"  " => Some(Self::Unchanged(contents)),
+            b"- " => Some(Self::Removed(contents)),
+            b"+ " => Some(Self::Added(contents)),
+            b"! " => Some(Self::Changed(contents)),
+            _ => None,
+        }
+    }
+
+    /// Serialize this line, including its change marker
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let (marker, contents): (&[u8], _) = match self {
+            Self::Unchanged(contents) => (b"  ", contents),
+            Self::Removed(contents) => (b"- ", contents),
+            Self::Added(contents) => (b"+ ", contents),
+            Self::Changed(contents) => (b"! ", contents),
+        };
+        if contents.ends_with(b"\n") {
+            [marker, contents].concat()
+        } else {
+            [marker, contents, b"\n", crate::parse::NO_NL].concat()
+        }
+    }
+
+    fn contents_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            Self::Unchanged(contents)
+            | Self::Removed(contents)
+            | Self::Added(contents)
+            | Self::Changed(contents) => contents,
+        }
+    }
+}
+
+/// A hunk in a context diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextHunk {
+    pub orig_pos: usize,
+    pub orig_range: usize,
+    pub mod_pos: usize,
+    pub mod_range: usize,
+
+    /// Lines of the original side of the hunk; empty if that side only has
+    /// unchanged lines
+    pub orig_lines: Vec<ContextLine>,
+
+    /// Lines of the modified side of the hunk; empty if that side only has
+    /// unchanged lines
+    pub mod_lines: Vec<ContextLine>,
+}
+
+/// Parse a `*** 1,4 ****` or `--- 1,4 ----` hunk header
+///
+/// # Returns
+/// The first line number, and the last one if it is given
+fn parse_range(line: &[u8], prefix: &[u8], suffix: &[u8]) -> Option<(usize, Option<usize>)> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let range = line.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    let number = |s: &[u8]| std::str::from_utf8(s).ok()?.parse().ok();
+    match range.split(|&c| c == b',').collect::<Vec<_>>()[..] {
+        [start] => Some((number(start)?, None)),
+        [start, end] => Some((number(start)?, Some(number(end)?))),
+        _ => None,
+    }
+}
+
+/// Read the lines of one side of a context diff hunk
+fn parse_section<'a, I>(lines: &mut std::iter::Peekable<I>) -> Vec<ContextLine>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    let mut section: Vec<ContextLine> = Vec::new();
+    while let Some(line) = lines
+        .next_if(|line| line.starts_with(b"\\") || ContextLine::parse_line(line).is_some())
+    {
+        if line.starts_with(b"\\") {
+            if line == crate::parse::NO_NL {
+                if let Some(last) = section.last_mut() {
+                    if last.contents_mut().ends_with(b"\n") {
+                        last.contents_mut().pop();
+                    }
+                }
+            }
+            continue;
+        }
+        section.extend(ContextLine::parse_line(line));
+    }
+    section
+}
+
+impl ContextHunk {
+    /// Parse a hunk, starting at the line after the `***************` separator
+    fn parse<'a, I>(lines: &mut std::iter::Peekable<I>) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a [u8]>,
+    {
+        let header = lines
+            .next()
+            .ok_or_else(|| Error::PatchSyntax("Unexpected end of hunk", vec![]))?;
+        let (orig_pos, _) = parse_range(header, b"*** ", b" ****").ok_or_else(|| {
+            Error::MalformedHunkHeader("Invalid original range", header.to_vec())
+        })?;
+        let orig_lines = parse_section(lines);
+        let header = lines
+            .next()
+            .ok_or_else(|| Error::PatchSyntax("Unexpected end of hunk", vec![]))?;
+        let (mod_pos, _) = parse_range(header, b"--- ", b" ----").ok_or_else(|| {
+            Error::MalformedHunkHeader("Invalid modified range", header.to_vec())
+        })?;
+        let mod_lines = parse_section(lines);
+        let mut hunk = ContextHunk {
+            orig_pos,
+            orig_range: 0,
+            mod_pos,
+            mod_range: 0,
+            orig_lines,
+            mod_lines,
+        };
+        // The ranges in the header are ambiguous for a single line, so count them
+        let lines = hunk.unified_lines();
+        hunk.orig_range = lines.iter().filter(|l| !matches!(l, HunkLine::InsertLine(_))).count();
+        hunk.mod_range = lines.iter().filter(|l| !matches!(l, HunkLine::RemoveLine(_))).count();
+        Ok(hunk)
+    }
+
+    /// The lines of this hunk, as they would appear in a unified diff
+    fn unified_lines(&self) -> Vec<HunkLine> {
+        // A side that is left out only has unchanged lines, which can be
+        // recovered from the other side
+        let derive = |lines: &[ContextLine], skip: fn(&ContextLine) -> bool| {
+            lines.iter().filter(|l| !skip(l)).cloned().collect::<Vec<_>>()
+        };
+        let orig = if self.orig_lines.is_empty() {
+            derive(&self.mod_lines, |l| matches!(l, ContextLine::Added(_)))
+        } else {
+            self.orig_lines.clone()
+        };
+        let modified = if self.mod_lines.is_empty() {
+            derive(&self.orig_lines, |l| matches!(l, ContextLine::Removed(_)))
+        } else {
+            self.mod_lines.clone()
+        };
+
+        let mut lines = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        loop {
+            match (orig.get(i), modified.get(j)) {
+                (Some(ContextLine::Removed(line)), _) => {
+                    lines.push(HunkLine::RemoveLine(line.clone()));
+                    i += 1;
+                }
+                (_, Some(ContextLine::Added(line))) => {
+                    lines.push(HunkLine::InsertLine(line.clone()));
+                    j += 1;
+                }
+                (Some(ContextLine::Changed(_)), _) | (_, Some(ContextLine::Changed(_))) => {
+                    while let Some(ContextLine::Changed(line)) = orig.get(i) {
+                        lines.push(HunkLine::RemoveLine(line.clone()));
+                        i += 1;
+                    }
+                    while let Some(ContextLine::Changed(line)) = modified.get(j) {
+                        lines.push(HunkLine::InsertLine(line.clone()));
+                        j += 1;
+                    }
+                }
+                (Some(ContextLine::Unchanged(line)), other) => {
+                    lines.push(HunkLine::ContextLine(line.clone()));
+                    i += 1;
+                    j += usize::from(other.is_some());
+                }
+                (None, Some(ContextLine::Unchanged(line))) => {
+                    lines.push(HunkLine::ContextLine(line.clone()));
+                    j += 1;
+                }
+                (None, None) => break,
+                // Added lines on the original side or removed lines on the
+                // modified side are meaningless; skip them
+                (Some(ContextLine::Added(_)), _) => i += 1,
+                (None, Some(ContextLine::Removed(_))) => j += 1,
+            }
+        }
+        lines
+    }
+
+    /// Convert this hunk to a unified diff hunk
+    ///
+    /// Changed lines become removed lines followed by inserted lines.
+    pub fn to_hunk(&self) -> Hunk {
+        let mut hunk = Hunk::new(self.orig_pos, self.orig_range, self.mod_pos, self.mod_range, None);
+        hunk.lines = self.unified_lines();
+        hunk
+    }
+
+    /// Convert a unified diff hunk to a context diff hunk
+    ///
+    /// A run of removed and inserted lines between two unchanged lines becomes
+    /// changed lines on both sides. A side with only unchanged lines is left out.
+    pub fn from_hunk(hunk: &Hunk) -> Self {
+        let mut orig_lines = Vec::new();
+        let mut mod_lines = Vec::new();
+        let mut removed = Vec::new();
+        let mut inserted = Vec::new();
+        // Move a run of removed and inserted lines to the two sides
+        let flush = |removed: &mut Vec<Vec<u8>>,
+                     inserted: &mut Vec<Vec<u8>>,
+                     orig_lines: &mut Vec<ContextLine>,
+                     mod_lines: &mut Vec<ContextLine>| {
+            let changed = !removed.is_empty() && !inserted.is_empty();
+            orig_lines.extend(removed.drain(..).map(|line| match changed {
+                true => ContextLine::Changed(line),
+                false => ContextLine::Removed(line),
+            }));
+            mod_lines.extend(inserted.drain(..).map(|line| match changed {
+                true => ContextLine::Changed(line),
+                false => ContextLine::Added(line),
+            }));
+        };
+        for line in &hunk.lines {
+            match line {
+                HunkLine::ContextLine(line) => {
+                    flush(&mut removed, &mut inserted, &mut orig_lines, &mut mod_lines);
+                    orig_lines.push(ContextLine::Unchanged(line.clone()));
+                    mod_lines.push(ContextLine::Unchanged(line.clone()));
+                }
+                HunkLine::RemoveLine(line) => removed.push(line.clone()),
+                HunkLine::InsertLine(line) => inserted.push(line.clone()),
+            }
+        }
+        flush(&mut removed, &mut inserted, &mut orig_lines, &mut mod_lines);
+        for lines in [&mut orig_lines, &mut mod_lines] {
+            if lines.iter().all(|line| matches!(line, ContextLine::Unchanged(_))) {
+                lines.clear();
+            }
+        }
+        ContextHunk {
+            orig_pos: hunk.orig_pos,
+            orig_range: hunk.orig_range,
+            mod_pos: hunk.mod_pos,
+            mod_range: hunk.mod_range,
+            orig_lines,
+            mod_lines,
+        }
+    }
+
+    /// Serialize this hunk, including the `***************` separator
+    pub fn as_bytes(&self) -> Vec<u8> {
+        // A single line range (or an empty one) is written as just a line number
+        let range = |pos: usize, range: usize| {
+            if range <= 1 {
+                format!("{}", pos)
+            } else {
+                format!("{},{}", pos, pos + range - 1)
+            }
+        };
+        let mut bytes = b"***************\n".to_vec();
+        bytes.extend(format!("*** {} ****\n", range(self.orig_pos, self.orig_range)).into_bytes());
+        bytes.extend(self.orig_lines.iter().flat_map(|line| line.as_bytes()));
+        bytes.extend(format!("--- {} ----\n", range(self.mod_pos, self.mod_range)).into_bytes());
+        bytes.extend(self.mod_lines.iter().flat_map(|line| line.as_bytes()));
+        bytes
+    }
+}
+
+/// A patch for a single file in the context diff format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextPatch {
+    pub orig_name: Vec<u8>,
+    pub orig_ts: Option<Vec<u8>>,
+    pub mod_name: Vec<u8>,
+    pub mod_ts: Option<Vec<u8>>,
+    pub hunks: Vec<ContextHunk>,
+}
+
+fn invalid_name(line: &[u8]) -> Error {
+    Error::MalformedPatchHeader("Invalid file name", line.to_vec())
+}
+
+impl ContextPatch {
+    /// Parse a single context diff
+    ///
+    /// Any lines before the `***` header are skipped.
+    pub fn parse<'a, I>(iter_lines: I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a [u8]>,
+    {
+        let mut lines = iter_lines.peekable();
+        let line = lines
+            .find(|line| line.starts_with(b"*** "))
+            .ok_or_else(|| Error::PatchSyntax("No input", vec![]))?;
+        let (orig_name, orig_ts) = crate::parse::parse_name_line(line, b"*** ", "No orig name", invalid_name)?;
+        let line = lines
+            .next()
+            .ok_or_else(|| Error::PatchSyntax("No input", vec![]))?;
+        let (mod_name, mod_ts) = crate::parse::parse_name_line(line, b"--- ", "No mod line", invalid_name)?;
+        let mut hunks = Vec::new();
+        while lines.next_if(|line| line.starts_with(b"***************")).is_some() {
+            hunks.push(ContextHunk::parse(&mut lines)?);
+        }
+        Ok(ContextPatch { orig_name, orig_ts, mod_name, mod_ts, hunks })
+    }
+
+    /// Convert this patch to a unified diff
+    pub fn to_unified(&self) -> UnifiedPatch {
+        let mut patch = UnifiedPatch::new(
+            self.orig_name.clone(),
+            self.orig_ts.clone(),
+            self.mod_name.clone(),
+            self.mod_ts.clone(),
+        );
+        patch.hunks = self.hunks.iter().map(|hunk| hunk.to_hunk()).collect();
+        patch
+    }
+
+    /// Serialize this patch as a context diff
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (prefix, name, ts) in [
+            (&b"*** "[..], &self.orig_name, &self.orig_ts),
+            (&b"--- "[..], &self.mod_name, &self.mod_ts),
+        ] {
+            bytes.extend_from_slice(prefix);
+            bytes.extend_from_slice(name);
+            if let Some(ts) = ts {
+                bytes.push(b'\t');
+                bytes.extend_from_slice(ts);
+            }
+            bytes.push(b'\n');
+        }
+        bytes.extend(self.hunks.iter().flat_map(|hunk| hunk.as_bytes()));
+        bytes
+    }
+}
+
+impl UnifiedPatch {
+    /// Convert this patch to a context diff
+    pub fn to_context(&self) -> ContextPatch {
+        ContextPatch {
+            orig_name: self.orig_name.clone(),
+            orig_ts: self.orig_ts.clone(),
+            mod_name: self.mod_name.clone(),
+            mod_ts: self.mod_ts.clone(),
+            hunks: self.hunks.iter().map(ContextHunk::from_hunk).collect(),
+        }
+    }
+}
+
+impl Patch for ContextPatch {
+    fn oldname(&self) -> &[u8] {
+        &self.orig_name
+    }
+
+    fn newname(&self) -> &[u8] {
+        &self.mod_name
+    }
+
+    fn apply_exact(&self, orig: &[u8]) -> Result<Vec<u8>, ApplyError> {
+        self.to_unified().apply_exact(orig)
+    }
+
+    fn apply(&self, orig: &[u8]) -> ApplyResult {
+        self.to_unified().apply(orig)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContextHunk, ContextLine, ContextPatch};
+    use crate::parse::splitlines;
+    use crate::patch::{HunkLine, Patch, UnifiedPatch};
+
+    macro_rules! test_context_patch {
+        ($name:ident, $orig:expr, $mod:expr, $patch:expr) => {
+            #[test]
+            fn $name() {
+                let orig = include_bytes!(concat!("../test_patches_data/", $orig));
+                let modi = include_bytes!(concat!("../test_patches_data/", $mod));
+                let patch = include_bytes!(concat!("../test_patches_data/", $patch));
+                let parsed = ContextPatch::parse(splitlines(patch)).unwrap();
+                assert_eq!(parsed.apply_exact(orig).unwrap(), modi);
+            }
+        };
+    }
+
+    test_context_patch!(test_context, "orig", "mod", "context");
+    test_context_patch!(test_context_2, "orig-2", "mod-2", "context-2");
+    test_context_patch!(test_context_3, "orig-3", "mod-3", "context-3");
+    test_context_patch!(test_context_4, "orig-4", "mod-4", "context-4");
+    test_context_patch!(test_context_5, "orig-5", "mod-5", "context-5");
+    test_context_patch!(test_context_6, "orig-6", "mod-6", "context-6");
+    test_context_patch!(test_context_7, "orig-7", "mod-7", "context-7");
+
+    #[test]
+    fn test_parse() {
+        let patch = ContextPatch::parse(splitlines(
+            b"*** a.txt\t2024-01-01\n--- b.txt\t2024-01-02\n***************\n*** 1,3 ****\n  a\n! b\n- c\n--- 1,2 ----\n  a\n! B\n",
+        ))
+        .unwrap();
+        assert_eq!(patch.orig_name, b"a.txt");
+        assert_eq!(patch.mod_ts, Some(b"2024-01-02".to_vec()));
+        assert_eq!(
+            patch.hunks,
+            vec![ContextHunk {
+                orig_pos: 1,
+                orig_range: 3,
+                mod_pos: 1,
+                mod_range: 2,
+                orig_lines: vec![
+                    ContextLine::Unchanged(b"a\n".to_vec()),
+                    ContextLine::Changed(b"b\n".to_vec()),
+                    ContextLine::Removed(b"c\n".to_vec()),
+                ],
+                mod_lines: vec![
+                    ContextLine::Unchanged(b"a\n".to_vec()),
+                    ContextLine::Changed(b"B\n".to_vec()),
+                ],
+            }]
+        );
+        assert_eq!(
+            patch.hunks[0].to_hunk().lines,
+            vec![
+                HunkLine::ContextLine(b"a\n".to_vec()),
+                HunkLine::RemoveLine(b"b\n".to_vec()),
+                HunkLine::InsertLine(b"B\n".to_vec()),
+                HunkLine::RemoveLine(b"c\n".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_unified() {
+        let patch = ContextPatch::parse(splitlines(include_bytes!("../test_patches_data/context-4")))
+            .unwrap();
+        let unified = patch.to_unified();
+        assert_eq!(unified.orig_name, b"orig-4");
+        assert_eq!(unified.mod_ts, patch.mod_ts);
+        assert_eq!(
+            unified.apply_exact(include_bytes!("../test_patches_data/orig-4")).unwrap(),
+            include_bytes!("../test_patches_data/mod-4")
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"--- a.txt\t2024-01-01\n+++ b.txt\n@@ -1,4 +1,3 @@\n a\n-b\n+B\n c\n-d\n";
+        let unified = UnifiedPatch::parse_patch(splitlines(data), false).unwrap();
+        let context = unified.to_context();
+        assert_eq!(
+            context.as_bytes(),
+            b"*** a.txt\t2024-01-01\n--- b.txt\n***************\n*** 1,4 ****\n  a\n! b\n  c\n- d\n--- 1,3 ----\n  a\n! B\n  c\n"
+        );
+        let reparsed = ContextPatch::parse(splitlines(&context.as_bytes())).unwrap();
+        assert_eq!(reparsed, context);
+        assert_eq!(reparsed.to_unified().hunks, unified.hunks);
+    }
+
+    #[test]
+    fn test_from_pure_insertion() {
+        let data = b"--- a\n+++ a\n@@ -1,2 +1,3 @@\n a\n+new\n b\n";
+        let unified = UnifiedPatch::parse_patch(splitlines(data), false).unwrap();
+        let context = unified.to_context();
+        assert!(context.hunks[0].orig_lines.is_empty());
+        assert_eq!(
+            context.hunks[0].as_bytes(),
+            b"***************\n*** 1,2 ****\n--- 1,3 ----\n  a\n+ new\n  b\n"
+        );
+        assert_eq!(context.to_unified().hunks, unified.hunks);
+    }
+}
@@ -0,0 +1,247 @@
+//! Generation of unified diffs from two versions of a file
+use crate::patch::{Hunk, HunkLine, UnifiedPatch};
+
+/// Number of context lines `diff_unified` puts around each change
+pub const DEFAULT_CONTEXT_LINES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffOp {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Compute the shortest edit script between two sequences of lines
+///
+/// This uses Myers' O(ND) algorithm, which keeps the common case of
+/// small changes to large files cheap. Only the diagonals reachable at each
+/// step are kept for the backtrace, so memory use is O(D^2) rather than
+/// O((N+M)D).
+fn diff_ops(a: &[&[u8]], b: &[&[u8]]) -> Vec<DiffOp> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 3];
+    let mut trace = Vec::new();
+
+    'outer: for d in 0..=max as isize {
+        // Diagonals -d-1..=d+1 are all that the backtrace reads for step d
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+            k += 2;
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(DiffOp::Equal(x as usize - 1, y as usize - 1));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                ops.push(DiffOp::Insert(y as usize - 1));
+            } else {
+                ops.push(DiffOp::Delete(x as usize - 1));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+/// Compute the hunks that turn `orig` into `modified`
+///
+/// # Arguments
+/// * `orig` - The original contents
+/// * `modified` - The modified contents
+/// * `context` - Number of unchanged lines to include around each change
+pub fn diff_hunks(orig: &[u8], modified: &[u8], context: usize) -> Vec<Hunk> {
+    let a = crate::parse::splitlines(orig).collect::<Vec<_>>();
+    let b = crate::parse::splitlines(modified).collect::<Vec<_>>();
+    let ops = diff_ops(&a, &b);
+
+    // Positions in the original and modified file before each op
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for op in &ops {
+        positions.push((i, j));
+        match op {
+            DiffOp::Equal(..) => {
+                i += 1;
+                j += 1;
+            }
+            DiffOp::Delete(_) => i += 1,
+            DiffOp::Insert(_) => j += 1,
+        }
+    }
+    positions.push((i, j));
+
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, DiffOp::Equal(..)))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    // Group changes that are close enough to share context into hunks
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for index in changes {
+        match groups.last_mut() {
+            Some((_, last)) if index - *last <= 2 * context + 1 => *last = index,
+            _ => groups.push((index, index)),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(first, last)| {
+            let start = first.saturating_sub(context);
+            let end = (last + context + 1).min(ops.len());
+            let (orig_start, mod_start) = positions[start];
+            let (orig_end, mod_end) = positions[end];
+            let orig_range = orig_end - orig_start;
+            let mod_range = mod_end - mod_start;
+            // An empty range refers to the line before the (empty) hunk
+            let orig_pos = if orig_range == 0 { orig_start } else { orig_start + 1 };
+            let mod_pos = if mod_range == 0 { mod_start } else { mod_start + 1 };
+            let mut hunk = Hunk::new(orig_pos, orig_range, mod_pos, mod_range, None);
+            hunk.lines = ops[start..end]
+                .iter()
+                .map(|op| match *op {
+                    DiffOp::Equal(i, _) => HunkLine::ContextLine(a[i].to_vec()),
+                    DiffOp::Delete(i) => HunkLine::RemoveLine(a[i].to_vec()),
+                    DiffOp::Insert(j) => HunkLine::InsertLine(b[j].to_vec()),
+                })
+                .collect();
+            hunk
+        })
+        .collect()
+}
+
+/// Generate a unified diff between two versions of a file
+///
+/// # Arguments
+/// * `orig_name` - Name to use for the original file
+/// * `mod_name` - Name to use for the modified file
+/// * `orig` - The original contents
+/// * `modified` - The modified contents
+pub fn diff_unified(
+    orig_name: &[u8],
+    mod_name: &[u8],
+    orig: &[u8],
+    modified: &[u8],
+) -> UnifiedPatch {
+    let mut patch = UnifiedPatch::new(orig_name.to_vec(), None, mod_name.to_vec(), None);
+    patch.hunks = diff_hunks(orig, modified, DEFAULT_CONTEXT_LINES);
+    patch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_hunks, diff_unified};
+    use crate::patch::{Hunk, HunkLine, Patch};
+
+    #[test]
+    fn test_identical() {
+        assert_eq!(diff_hunks(b"a\nb\n", b"a\nb\n", 3), vec![]);
+    }
+
+    #[test]
+    fn test_change() {
+        let hunks = diff_hunks(b"a\nb\nc\n", b"a\nB\nc\n", 3);
+        let mut expected = Hunk::new(1, 3, 1, 3, None);
+        expected.lines.extend([
+            HunkLine::ContextLine(b"a\n".to_vec()),
+            HunkLine::RemoveLine(b"b\n".to_vec()),
+            HunkLine::InsertLine(b"B\n".to_vec()),
+            HunkLine::ContextLine(b"c\n".to_vec()),
+        ]);
+        assert_eq!(hunks, vec![expected]);
+    }
+
+    #[test]
+    fn test_separate_hunks() {
+        let orig = (1..=20).map(|i| format!("{}\n", i)).collect::<String>();
+        let modified = (1..=20)
+            .map(|i| match i {
+                2 => "two\n".to_string(),
+                18 => "eighteen\n".to_string(),
+                i => format!("{}\n", i),
+            })
+            .collect::<String>();
+        let hunks = diff_hunks(orig.as_bytes(), modified.as_bytes(), 3);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!((hunks[0].orig_pos, hunks[0].orig_range), (1, 5));
+        assert_eq!((hunks[1].orig_pos, hunks[1].orig_range), (15, 6));
+    }
+
+    #[test]
+    fn test_from_empty() {
+        let hunks = diff_hunks(b"", b"a\n", 3);
+        assert_eq!(hunks[0].get_header(), b"@@ -0,0 +1 @@\n");
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let orig = include_bytes!("../test_patches_data/orig-2");
+        let modified = include_bytes!("../test_patches_data/mod-2");
+        let patch = diff_unified(b"orig", b"mod", orig, modified);
+        assert_eq!(patch.apply_exact(orig).unwrap(), modified);
+    }
+
+    #[test]
+    fn test_roundtrip_large() {
+        let orig = (0..1000).map(|i| format!("{}\n", i)).collect::<String>();
+        let modified = (0..1000)
+            .filter(|i| i % 97 != 3)
+            .flat_map(|i| match i % 101 {
+                7 => vec![format!("{}\n", i), format!("new {}\n", i)],
+                _ => vec![format!("{}\n", i)],
+            })
+            .chain(["end\n".to_string()])
+            .collect::<String>();
+        let patch = diff_unified(b"a", b"b", orig.as_bytes(), modified.as_bytes());
+        assert_eq!(patch.apply_exact(orig.as_bytes()).unwrap(), modified.as_bytes());
+    }
+
+    #[test]
+    fn test_no_newline_at_end() {
+        let patch = diff_unified(b"a", b"b", b"x\ny", b"x\ny\n");
+        assert_eq!(
+            patch.as_bytes(),
+            b"--- a\n+++ b\n@@ -1,2 +1,2 @@\n x\n-y\n\\ No newline at end of file\n+y\n"
+        );
+    }
+}
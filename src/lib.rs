@@ -2,3 +2,5 @@ pub mod quilt;
 pub mod timestamp;
 pub mod patch;
 pub mod parse;
+pub mod diff;
+pub mod context;
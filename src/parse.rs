@@ -1,4 +1,4 @@
-use crate::patch::{Hunk, HunkLine, Patch, UnifiedPatch, BinaryPatch};
+use crate::patch::{ApplyOptions, Hunk, HunkLine, Patch, UnifiedPatch, BinaryPatch};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
@@ -6,6 +6,8 @@ pub enum Error {
     PatchSyntax(&'static str, Vec<u8>),
     MalformedPatchHeader(&'static str, Vec<u8>),
     MalformedHunkHeader(&'static str, Vec<u8>),
+    LimitExceeded(&'static str, usize),
+    UnsupportedFormat(DiffFormat),
 }
 
 impl std::fmt::Display for Error {
@@ -15,6 +17,8 @@ impl std::fmt::Display for Error {
             Self::PatchSyntax(msg, line) => write!(f, "Patch syntax error: {} in {:?}", msg, line),
             Self::MalformedPatchHeader(msg, line) => write!(f, "Malformed patch header: {} in {:?}", msg, line),
             Self::MalformedHunkHeader(msg, line) => write!(f, "Malformed hunk header: {} in {:?}", msg, line),
+            Self::LimitExceeded(what, limit) => write!(f, "Limit exceeded: {} is over {}", what, limit),
+            Self::UnsupportedFormat(format) => write!(f, "Unsupported diff format: {:?}", format),
         }
     }
 }
@@ -73,240 +77,1868 @@ mod splitlines_tests {
     }
 }
 
+/// Split lines on any common line terminator, preserving the terminators
+///
+/// Unlike `splitlines`, this recognizes `\r\n` and a lone `\r` (as used on classic
+/// Mac OS) as line breaks in addition to `\n`.
+pub fn splitlines_any(data: &[u8]) -> impl Iterator<Item = &'_ [u8]> {
+    let mut start = 0;
+    std::iter::from_fn(move || {
+        if start == data.len() {
+            return None;
+        }
+        let mut end = start;
+        while end < data.len() {
+            match data[end] {
+                b'\n' => {
+                    end += 1;
+                    break;
+                }
+                b'\r' => {
+                    end += 1;
+                    if data.get(end) == Some(&b'\n') {
+                        end += 1;
+                    }
+                    break;
+                }
+                _ => end += 1,
+            }
+        }
+        let line = &data[start..end];
+        start = end;
+        Some(line)
+    })
+}
+
+#[cfg(test)]
+mod splitlines_any_tests {
+    #[test]
+    fn test_mixed() {
+        let data = b"unix\ndos\r\nmac\rlast";
+        let lines: Vec<&[u8]> = super::splitlines_any(data).collect();
+        assert_eq!(
+            lines,
+            vec![&b"unix\n"[..], &b"dos\r\n"[..], &b"mac\r"[..], &b"last"[..]]
+        );
+    }
+
+    #[test]
+    fn test_empty_lines() {
+        let data = b"\r\r\n\n";
+        let lines: Vec<&[u8]> = super::splitlines_any(data).collect();
+        assert_eq!(lines, vec![&b"\r"[..], &b"\r\n"[..], &b"\n"[..]]);
+    }
+}
+
+/// Decode quoted-printable content, as found in the body of a mailed patch
+///
+/// `=` at the end of a line joins it with the next line, and `=XX` is replaced by the
+/// byte with hex value `XX`. An `=` that is not followed by either is kept as-is.
+pub fn decode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let hex = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let mut ret = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != b'=' {
+            ret.push(data[i]);
+            i += 1;
+            continue;
+        }
+        match &data[i + 1..] {
+            [b'\n', ..] => i += 2,
+            [b'\r', b'\n', ..] => i += 3,
+            [hi, lo, ..] if hex(*hi).is_some() && hex(*lo).is_some() => {
+                ret.push(hex(*hi).unwrap() << 4 | hex(*lo).unwrap());
+                i += 3;
+            }
+            _ => {
+                ret.push(b'=');
+                i += 1;
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod decode_quoted_printable_tests {
+    use super::decode_quoted_printable;
+
+    #[test]
+    fn test_diff() {
+        let encoded = b"--- a/foo\n+++ b/foo\n@@ -1 +1 @@\n-if x =3D=3D 1:\n+if x =3D=3D 2: # a very long line that has been wr=\napped\n+caf=C3=A9=20\n";
+        assert_eq!(
+            decode_quoted_printable(encoded),
+            "--- a/foo\n+++ b/foo\n@@ -1 +1 @@\n-if x == 1:\n+if x == 2: # a very long line that has been wrapped\n+caf\u{e9} \n".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_invalid_escape() {
+        assert_eq!(decode_quoted_printable(b"a=zz=\r\nb="), b"a=zzb=");
+    }
+}
+
+/// The format of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffFormat {
+    /// Unified diff (`diff -u`)
+    Unified,
+
+    /// Context diff (`diff -c`)
+    Context,
+
+    /// Ed script (`diff -e`)
+    Ed,
+
+    /// Normal diff (plain `diff`)
+    Normal,
+}
+
+/// Number of lines inspected by `sniff_format`
+const SNIFF_LINES: usize = 50;
+
+static ED_COMMAND_RE: once_cell::sync::Lazy<regex::bytes::Regex> =
+    lazy_regex::bytes_lazy_regex!(r"^\d+(,\d+)?[acd]\r?\n?$");
+static NORMAL_COMMAND_RE: once_cell::sync::Lazy<regex::bytes::Regex> =
+    lazy_regex::bytes_lazy_regex!(r"^\d+(,\d+)?[acd]\d+(,\d+)?\r?\n?$");
+
+/// Guess the format of a diff by looking at its first few lines
+///
+/// This is a cheap check that does not parse the diff; it only looks for
+/// the headers that are characteristic for each format.
+///
+/// # Returns
+/// The detected format, or `None` if the data does not look like a diff
+pub fn sniff_format(data: &[u8]) -> Option<DiffFormat> {
+    let mut prev: Option<&[u8]> = None;
+    for line in splitlines(data).take(SNIFF_LINES) {
+        if let Some(prev) = prev {
+            if prev.starts_with(b"--- ") && line.starts_with(b"+++ ") {
+                return Some(DiffFormat::Unified);
+            }
+            if prev.starts_with(b"*** ") && line.starts_with(b"--- ") {
+                return Some(DiffFormat::Context);
+            }
+        }
+        if line.starts_with(b"@@ -") {
+            return Some(DiffFormat::Unified);
+        }
+        if line.starts_with(b"***************") {
+            return Some(DiffFormat::Context);
+        }
+        if NORMAL_COMMAND_RE.is_match(line) {
+            return Some(DiffFormat::Normal);
+        }
+        if ED_COMMAND_RE.is_match(line) {
+            return Some(DiffFormat::Ed);
+        }
+        prev = Some(line);
+    }
+    None
+}
+
+#[cfg(test)]
+mod sniff_format_tests {
+    use super::{sniff_format, DiffFormat};
+
+    #[test]
+    fn test_unified() {
+        let data = b"--- a/foo\n+++ b/foo\n@@ -1 +1 @@\n-a\n+b\n";
+        assert_eq!(sniff_format(data), Some(DiffFormat::Unified));
+    }
+
+    #[test]
+    fn test_context() {
+        let data = b"*** a/foo\n--- b/foo\n***************\n*** 1 ****\n! a\n--- 1 ----\n! b\n";
+        assert_eq!(sniff_format(data), Some(DiffFormat::Context));
+    }
+
+    #[test]
+    fn test_ed() {
+        let data = b"2c\nchanged\n.\n";
+        assert_eq!(sniff_format(data), Some(DiffFormat::Ed));
+    }
+
+    #[test]
+    fn test_normal() {
+        let data = b"2c2\n< a\n---\n> b\n";
+        assert_eq!(sniff_format(data), Some(DiffFormat::Normal));
+    }
+
+    #[test]
+    fn test_not_a_diff() {
+        assert_eq!(sniff_format(b"just some text\n"), None);
+    }
+}
+
 pub const NO_NL: &[u8] = b"\\ No newline at end of file\n";
 
 /// Iterate through a series of lines, ensuring that lines
 /// that originally had no terminating newline are produced
 /// without one.
-pub fn iter_lines_handle_nl<'a, I>(mut iter_lines: I) -> impl Iterator<Item = &'a [u8]> + 'a
+pub fn iter_lines_handle_nl<'a, I>(iter_lines: I) -> impl Iterator<Item = &'a [u8]> + 'a
+where
+    I: Iterator<Item = &'a [u8]> + 'a
+{
+    iter_lines_with_nl_flag(iter_lines).map(|(line, _)| line)
+}
+
+/// Iterate through a series of lines, removing the newline from lines that are
+/// followed by a `\ No newline at end of file` marker.
+///
+/// Each line is yielded along with a flag that is `false` if the line was followed
+/// by the marker, so that serializers can tell where to emit the marker again.
+pub fn iter_lines_with_nl_flag<'a, I>(mut iter_lines: I) -> impl Iterator<Item = (&'a [u8], bool)> + 'a
 where
     I: Iterator<Item = &'a [u8]> + 'a
 {
-    let mut last_line: Option<&'a [u8]> = None;
+    let mut last_line: Option<(&'a [u8], bool)> = None;
     std::iter::from_fn(move || {
         for line in iter_lines.by_ref() {
             if line == NO_NL {
-                if let Some(last) = last_line.as_mut() {
+                if let Some((last, has_nl)) = last_line.as_mut() {
                     assert!(last.ends_with(b"\n"));
                     // Drop the last newline from `last`
                     *last = &last[..last.len() - 1];
+                    *has_nl = false;
                 } else {
                     panic!("No newline indicator without previous line");
                 }
             } else {
                 if let Some(last) = last_line.take() {
-                    last_line = Some(line);
+                    last_line = Some((line, true));
                     return Some(last);
                 }
-                last_line = Some(line);
+                last_line = Some((line, true));
+            }
+        }
+        last_line.take()
+    })
+}
+
+#[test]
+fn test_iter_lines_with_nl_flag() {
+    let lines = vec![
+        &b"-old\n"[..],
+        &b"\\ No newline at end of file\n"[..],
+        &b"+new\n"[..],
+        &b"+last"[..],
+    ];
+    let result = iter_lines_with_nl_flag(lines.into_iter()).collect::<Vec<_>>();
+    assert_eq!(
+        result,
+        vec![(&b"-old"[..], false), (&b"+new\n"[..], true), (&b"+last"[..], true)]
+    );
+}
+
+#[test]
+fn test_iter_lines_handle_nl() {
+    let lines = vec![
+        &b"line 1\n"[..],
+        &b"line 2\n"[..],
+        &b"line 3\n"[..],
+        &b"line 4\n"[..],
+        &b"\\ No newline at end of file\n"[..]
+    ];
+    let mut iter = iter_lines_handle_nl(lines.into_iter());
+    assert_eq!(iter.next(), Some("line 1\n".as_bytes()));
+    assert_eq!(iter.next(), Some("line 2\n".as_bytes()));
+    assert_eq!(iter.next(), Some("line 3\n".as_bytes()));
+    assert_eq!(iter.next(), Some("line 4".as_bytes()));
+    assert_eq!(iter.next(), None);
+}
+
+static BINARY_FILES_RE: once_cell::sync::Lazy<regex::bytes::Regex> = lazy_regex::bytes_lazy_regex!(r"^Binary files (.+) and (.+) differ");
+
+/// Remove C-style quoting from a path, as used by git for unusual file names
+///
+/// Paths that are not surrounded by double quotes are returned unchanged. Inside the
+/// quotes, backslash escapes (including three-digit octal escapes for non-ASCII bytes)
+/// are decoded.
+///
+/// # Returns
+/// The unquoted path, or `None` if the quoting is malformed
+pub fn unquote_path(path: &[u8]) -> Option<Vec<u8>> {
+    let inner = match path.strip_prefix(b"\"").and_then(|p| p.strip_suffix(b"\"")) {
+        Some(inner) => inner,
+        None => return Some(path.to_vec()),
+    };
+    let mut ret = Vec::with_capacity(inner.len());
+    let mut iter = inner.iter().copied();
+    while let Some(c) = iter.next() {
+        if c != b'\\' {
+            ret.push(c);
+            continue;
+        }
+        let escaped = match iter.next()? {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'v' => 0x0b,
+            b'f' => 0x0c,
+            b'r' => b'\r',
+            b'"' => b'"',
+            b'\\' => b'\\',
+            d @ b'0'..=b'3' => {
+                let mut value = (d - b'0') as u32;
+                for _ in 0..2 {
+                    match iter.next()? {
+                        d @ b'0'..=b'7' => value = value * 8 + (d - b'0') as u32,
+                        _ => return None,
+                    }
+                }
+                value as u8
+            }
+            _ => return None,
+        };
+        ret.push(escaped);
+    }
+    Some(ret)
+}
+
+/// Find the end of a double-quoted string at the start of `data`, returning the
+/// index just past the closing quote
+fn quoted_len(data: &[u8]) -> Option<usize> {
+    let mut i = 1;
+    while i < data.len() {
+        match data[i] {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Parse the file names from a `diff --git a/... b/...` line
+///
+/// Quoted names are unquoted. When neither name is quoted and the names contain
+/// spaces, the line is split so that both names refer to the same path if possible.
+///
+/// # Returns
+/// The old and new names (including their `a/` and `b/` prefixes), or `None` if the
+/// line is not a `diff --git` line
+pub fn parse_git_diff_header(line: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let rest = line.strip_prefix(b"diff --git ")?;
+    let rest = rest.strip_suffix(b"\n").unwrap_or(rest);
+    let rest = rest.strip_suffix(b"\r").unwrap_or(rest);
+
+    let (old, new) = if rest.starts_with(b"\"") {
+        let end = quoted_len(rest)?;
+        (&rest[..end], rest[end..].strip_prefix(b" ")?)
+    } else if rest.ends_with(b"\"") {
+        let start = rest.windows(2).rposition(|w| w == b" \"")?;
+        (&rest[..start], &rest[start + 1..])
+    } else {
+        // Prefer a split where both sides name the same file
+        let mid = rest.len() / 2;
+        if rest.len() % 2 == 1
+            && rest[mid] == b' '
+            && rest[..mid].get(2..) == rest[mid + 1..].get(2..)
+        {
+            (&rest[..mid], &rest[mid + 1..])
+        } else {
+            let start = rest
+                .windows(3)
+                .position(|w| w == b" b/")
+                .or_else(|| rest.iter().position(|&c| c == b' '))?;
+            (&rest[..start], &rest[start + 1..])
+        }
+    };
+    Some((unquote_path(old)?, unquote_path(new)?))
+}
+
+#[cfg(test)]
+mod git_header_tests {
+    use super::{parse_git_diff_header, unquote_path};
+
+    #[test]
+    fn test_unquote_path() {
+        assert_eq!(unquote_path(b"a/foo").unwrap(), b"a/foo");
+        assert_eq!(unquote_path(b"\"a/tab\\there\"").unwrap(), b"a/tab\there");
+        assert_eq!(unquote_path(b"\"a/p\\303\\251\"").unwrap(), "a/p\u{e9}".as_bytes());
+        assert_eq!(unquote_path(b"\"a/\\t\\\"x\\\"\"").unwrap(), b"a/\t\"x\"");
+        assert_eq!(unquote_path(b"\"a/bad\\q\""), None);
+    }
+
+    #[test]
+    fn test_plain() {
+        assert_eq!(
+            parse_git_diff_header(b"diff --git a/foo.rs b/foo.rs\n"),
+            Some((b"a/foo.rs".to_vec(), b"b/foo.rs".to_vec()))
+        );
+        assert_eq!(parse_git_diff_header(b"--- a/foo.rs\n"), None);
+    }
+
+    #[test]
+    fn test_spaces() {
+        assert_eq!(
+            parse_git_diff_header(b"diff --git a/with b/space b/with b/space\n"),
+            Some((b"a/with b/space".to_vec(), b"b/with b/space".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_quoted_octal() {
+        let (old, new) =
+            parse_git_diff_header(b"diff --git \"a/p\\303\\251\" \"b/p\\303\\251\"\n").unwrap();
+        assert_eq!(String::from_utf8(old).unwrap(), "a/p\u{e9}");
+        assert_eq!(String::from_utf8(new).unwrap(), "b/p\u{e9}");
+    }
+
+    #[test]
+    fn test_one_side_quoted() {
+        assert_eq!(
+            parse_git_diff_header(b"diff --git a/plain \"b/caf\\303\\251\"\n"),
+            Some((b"a/plain".to_vec(), "b/caf\u{e9}".as_bytes().to_vec()))
+        );
+    }
+}
+
+/// A file name and its optional timestamp, as found in a patch header
+type PatchName = (Vec<u8>, Option<Vec<u8>>);
+
+fn invalid_orig_name(line: &[u8]) -> Error {
+    Error::MalformedPatchHeader("No orig line", line.to_vec())
+}
+
+fn invalid_mod_name(line: &[u8]) -> Error {
+    Error::PatchSyntax("Invalid mod name", line.strip_prefix(b"+++ ").unwrap_or(line).to_vec())
+}
+
+/// Parse the `---` and `+++` lines of a patch
+///
+/// A `+++` line before the `---` line, as emitted by some broken tools, is
+/// accepted; a warning about it is added to `warnings`.
+fn get_patch_names<'a, T: Iterator<Item = &'a [u8]>>(
+    iter_lines: &mut T,
+    warnings: &mut Vec<String>,
+) -> Result<(PatchName, PatchName), Error> {
+    let line = iter_lines
+        .next()
+        .ok_or_else(|| Error::PatchSyntax("No input", vec![]))?;
+
+    if let Some(captures) = BINARY_FILES_RE.captures(line) {
+        let orig_name = captures.get(1).unwrap().as_bytes().to_vec();
+        let mod_name = captures.get(2).unwrap().as_bytes().to_vec();
+        return Err(Error::BinaryFiles(orig_name, mod_name));
+    }
+
+    if line.starts_with(b"+++ ") {
+        let mod_name = parse_name_line(line, b"+++ ", "No mod line", invalid_mod_name)?;
+        let line = iter_lines
+            .next()
+            .ok_or_else(|| Error::PatchSyntax("No input", vec![]))?;
+        let orig_name = parse_name_line(line, b"--- ", "No orig name", invalid_orig_name)?;
+        warnings.push(format!(
+            "+++ line before --- line for {}",
+            String::from_utf8_lossy(&orig_name.0)
+        ));
+        return Ok((orig_name, mod_name));
+    }
+
+    let orig_name = parse_name_line(line, b"--- ", "No orig name", invalid_orig_name)?;
+
+    let line = iter_lines
+        .next()
+        .ok_or_else(|| Error::PatchSyntax("No input", vec![]))?;
+
+    let mod_name = parse_name_line(line, b"+++ ", "No mod line", invalid_mod_name)?;
+
+    Ok((orig_name, mod_name))
+}
+
+/// Parse a `--- ` or `+++ ` header line into a file name and optional timestamp
+///
+/// `invalid` builds the error for a line with more than one tab.
+pub(crate) fn parse_name_line(
+    line: &[u8],
+    prefix: &[u8],
+    missing: &'static str,
+    invalid: fn(&[u8]) -> Error,
+) -> Result<PatchName, Error> {
+    let name = line
+        .strip_prefix(prefix)
+        .ok_or_else(|| Error::MalformedPatchHeader(missing, line.to_vec()))?
+        .strip_suffix(b"\n")
+        .ok_or_else(|| Error::PatchSyntax("missing newline", line.to_vec()))?;
+    match name.split(|&c| c == b'\t').collect::<Vec<_>>()[..] {
+        [name, ts] => Ok((name.to_vec(), Some(ts.to_vec()))),
+        [name] => Ok((name.to_vec(), None)),
+        _ => Err(invalid(line)),
+    }
+}
+
+#[cfg(test)]
+mod get_patch_names_tests {
+    #[test]
+    fn test_simple() {
+        let lines = [
+            &b"--- baz	2009-10-14 19:49:59 +0000\n"[..],
+            &b"+++ quxx	2009-10-14 19:51:00 +0000\n"[..]];
+        let mut iter = lines.into_iter();
+        let (old, new) = super::get_patch_names(&mut iter, &mut Vec::new()).unwrap();
+        assert_eq!(old, (b"baz".to_vec(), Some(b"2009-10-14 19:49:59 +0000".to_vec())));
+        assert_eq!(new, (b"quxx".to_vec(), Some(b"2009-10-14 19:51:00 +0000".to_vec())));
+    }
+
+    #[test]
+    fn test_windows_paths() {
+        let lines = [
+            &b"--- C:\\Users\\me\\file.txt\t2024-01-01 10:00:00 +0000\n"[..],
+            &b"+++ D:\\work dir\\file.txt\n"[..]];
+        let mut iter = lines.into_iter();
+        let (old, new) = super::get_patch_names(&mut iter, &mut Vec::new()).unwrap();
+        assert_eq!(old, (b"C:\\Users\\me\\file.txt".to_vec(), Some(b"2024-01-01 10:00:00 +0000".to_vec())));
+        assert_eq!(new, (b"D:\\work dir\\file.txt".to_vec(), None));
+    }
+
+    #[test]
+    fn test_binary() {
+        let lines = [
+            &b"Binary files qoo and bar differ\n"[..]
+        ];
+        let mut iter = lines.into_iter();
+        let e = super::get_patch_names(&mut iter, &mut Vec::new()).unwrap_err();
+        assert_eq!(e, super::Error::BinaryFiles(b"qoo".to_vec(), b"bar".to_vec()));
+    }
+
+    #[test]
+    fn test_swapped() {
+        let lines = [
+            &b"+++ b/foo\t2009-10-14 19:51:00 +0000\n"[..],
+            &b"--- a/foo\t2009-10-14 19:49:59 +0000\n"[..]];
+        let mut iter = lines.into_iter();
+        let mut warnings = Vec::new();
+        let (old, new) = super::get_patch_names(&mut iter, &mut warnings).unwrap();
+        assert_eq!(old, (b"a/foo".to_vec(), Some(b"2009-10-14 19:49:59 +0000".to_vec())));
+        assert_eq!(new, (b"b/foo".to_vec(), Some(b"2009-10-14 19:51:00 +0000".to_vec())));
+        assert_eq!(warnings, vec!["+++ line before --- line for a/foo"]);
+
+        let patch = super::UnifiedPatch::parse_patch(
+            super::splitlines(b"+++ b/foo\n--- a/foo\n@@ -1 +1 @@\n-x\n+y\n"),
+            false,
+        )
+        .unwrap();
+        assert_eq!(patch.orig_name, b"a/foo");
+        assert_eq!(patch.mod_name, b"b/foo");
+        assert_eq!(patch.hunks.len(), 1);
+        assert_eq!(patch.warnings, vec!["+++ line before --- line for a/foo"]);
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let lines = [&b"--- a\tb\tc\n"[..], &b"+++ a\n"[..]];
+        assert_eq!(
+            super::get_patch_names(&mut lines.into_iter(), &mut Vec::new()).unwrap_err(),
+            super::Error::MalformedPatchHeader("No orig line", b"--- a\tb\tc\n".to_vec())
+        );
+        let lines = [&b"--- a\n"[..], &b"+++ a\tb\tc\n"[..]];
+        assert_eq!(
+            super::get_patch_names(&mut lines.into_iter(), &mut Vec::new()).unwrap_err(),
+            super::Error::PatchSyntax("Invalid mod name", b"a\tb\tc\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_swapped_missing_orig() {
+        let lines = [&b"+++ b/foo\n"[..], &b"@@ -1 +1 @@\n"[..]];
+        let mut iter = lines.into_iter();
+        assert_eq!(
+            super::get_patch_names(&mut iter, &mut Vec::new()).unwrap_err(),
+            super::Error::MalformedPatchHeader("No orig name", b"@@ -1 +1 @@\n".to_vec())
+        );
+    }
+}
+
+/// Check whether a line is a form-feed page break, as inserted between file
+/// sections by some old tools
+fn is_page_break(line: &[u8]) -> bool {
+    line.strip_suffix(b"\n").unwrap_or(line) == b"\x0c"
+}
+
+pub fn iter_hunks<'a, I>(
+    iter_lines: &mut I,
+    allow_dirty: bool,
+) -> impl Iterator<Item = Result<Hunk, Error>> + '_
+where
+    I: Iterator<Item = &'a [u8]>
+{
+    let mut iter_lines = iter_lines.peekable();
+    std::iter::from_fn(move || {
+        while let Some(line) = iter_lines.next() {
+            if line == b"\n" || is_page_break(line) {
+                continue;
+            }
+            match Hunk::from_header(line) {
+                Ok(mut new_hunk) => {
+                    // A header without a newline can only be the last line of a
+                    // truncated patch; that is only complete if the hunk is empty
+                    if !line.ends_with(b"\n") {
+                        if new_hunk.orig_range == 0 && new_hunk.mod_range == 0 {
+                            return Some(Ok(new_hunk));
+                        }
+                        return Some(Err(Error::PatchSyntax("Unexpected end of hunk", new_hunk.get_header())));
+                    }
+                    let mut orig_size = 0;
+                    let mut mod_size = 0;
+                    // Carry on past the last line for a "\ No newline" marker after it
+                    while orig_size < new_hunk.orig_range
+                        || mod_size < new_hunk.mod_range
+                        || iter_lines.peek().is_some_and(|line| line.starts_with(b"\\"))
+                    {
+                        let line = match iter_lines.next() {
+                            Some(line) => line,
+                            None => {
+                                return Some(Err(Error::PatchSyntax("Unexpected end of hunk", new_hunk.get_header())));
+                            }
+                        };
+                        // Only the first character of a hunk line is significant; the
+                        // rest is content, even if it looks like a hunk header.
+                        if line.starts_with(b"\\") {
+                            if line == NO_NL {
+                                if let Some(
+                                    HunkLine::ContextLine(last)
+                                    | HunkLine::InsertLine(last)
+                                    | HunkLine::RemoveLine(last),
+                                ) = new_hunk.lines.last_mut()
+                                {
+                                    if last.ends_with(b"\n") {
+                                        last.pop();
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+                        match HunkLine::parse_line(line) {
+                            Err(_) => {
+                                return Some(Err(Error::PatchSyntax("Invalid hunk line", line.to_vec())));
+                            }
+                            Ok(hunk_line) => {
+                                if matches!(
+                                    hunk_line,
+                                    HunkLine::RemoveLine(_) | HunkLine::ContextLine(_)
+                                ) {
+                                    orig_size += 1
+                                }
+                                if matches!(
+                                    hunk_line,
+                                    HunkLine::InsertLine(_) | HunkLine::ContextLine(_)
+                                ) {
+                                    mod_size += 1
+                                }
+                                new_hunk.lines.push(hunk_line);
+                            }
+                        }
+                    }
+                    return Some(Ok(new_hunk));
+                }
+                Err(crate::patch::MalformedHunkHeader(m, l)) => {
+                    if allow_dirty {
+                        // If the line isn't a hunk header, then we've reached the end of this
+                        // patch and there's "junk" at the end. Ignore the rest of the patch.
+                        return None;
+                    } else {
+                        return Some(Err(Error::MalformedHunkHeader(m, l)));
+                    }
+                }
+            }
+        }
+        None
+    }
+    )
+}
+
+#[cfg(test)]
+mod iter_hunks_tests {
+    use super::{Hunk, HunkLine};
+
+    #[test]
+    fn test_trailing_no_newline() {
+        for allow_dirty in [false, true] {
+            let mut lines = super::splitlines(b"@@ -1 +1 @@\n-a\n+b\n\\ No newline at end of file\n");
+            let hunks = super::iter_hunks(&mut lines, allow_dirty)
+                .collect::<Result<Vec<Hunk>, _>>()
+                .unwrap();
+            let mut expected = Hunk::new(1, 1, 1, 1, None);
+            expected.lines.extend([
+                HunkLine::RemoveLine(b"a\n".to_vec()),
+                HunkLine::InsertLine(b"b".to_vec()),
+            ]);
+            assert_eq!(hunks, vec![expected]);
+        }
+    }
+    #[test]
+    fn test_iter_hunks() {
+        let mut lines = super::splitlines(br#"@@ -391,6 +391,8 @@
+                 else:
+                     assert isinstance(hunk_line, RemoveLine)
+                 line_no += 1
++    for line in orig_lines:
++        yield line
+                     
+ import unittest
+ import os.path
+
+"#);
+
+        let hunks = super::iter_hunks(&mut lines, false).collect::<Result<Vec<Hunk>, crate::parse::Error>>().unwrap();
+
+        let mut expected_hunk = Hunk::new(391, 6, 391, 8, None);
+        expected_hunk.lines.extend([
+            HunkLine::ContextLine(b"                else:\n".to_vec()),
+            HunkLine::ContextLine(b"                    assert isinstance(hunk_line, RemoveLine)\n".to_vec()),
+            HunkLine::ContextLine(b"                line_no += 1\n".to_vec()),
+            HunkLine::InsertLine(b"    for line in orig_lines:\n".to_vec()),
+            HunkLine::InsertLine(b"        yield line\n".to_vec()),
+            HunkLine::ContextLine(b"                    \n".to_vec()),
+            HunkLine::ContextLine(b"import unittest\n".to_vec()),
+            HunkLine::ContextLine(b"import os.path\n".to_vec())
+        ]);
+
+        assert_eq!(&expected_hunk, hunks.first().unwrap());
+    }
+
+    #[test]
+    fn test_content_looks_like_header() {
+        let mut lines = super::splitlines(
+            b"@@ -1,3 +1,3 @@\n @@ -5,6 +5,6 @@ not a header\n-@@@ old\n+@@@ new\n ## TODO\n@@ -10 +10 @@\n-a\n+b\n",
+        );
+
+        let hunks = super::iter_hunks(&mut lines, false).collect::<Result<Vec<Hunk>, crate::parse::Error>>().unwrap();
+
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(
+            hunks[0].lines,
+            vec![
+                HunkLine::ContextLine(b"@@ -5,6 +5,6 @@ not a header\n".to_vec()),
+                HunkLine::RemoveLine(b"@@@ old\n".to_vec()),
+                HunkLine::InsertLine(b"@@@ new\n".to_vec()),
+                HunkLine::ContextLine(b"## TODO\n".to_vec()),
+            ]
+        );
+        assert_eq!(hunks[1].orig_pos, 10);
+    }
+
+    #[test]
+    fn test_no_newline_marker() {
+        let mut lines = super::splitlines(b"@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+b\n");
+        let hunks = super::iter_hunks(&mut lines, false).collect::<Result<Vec<Hunk>, crate::parse::Error>>().unwrap();
+        assert_eq!(
+            hunks[0].lines,
+            vec![HunkLine::RemoveLine(b"a".to_vec()), HunkLine::InsertLine(b"b\n".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_truncated_hunk() {
+        let mut lines = super::splitlines(b"@@ -1,3 +1,3 @@\n a\n");
+        let err = super::iter_hunks(&mut lines, false).collect::<Result<Vec<Hunk>, crate::parse::Error>>().unwrap_err();
+        assert!(matches!(err, crate::parse::Error::PatchSyntax("Unexpected end of hunk", _)));
+    }
+}
+
+impl UnifiedPatch {
+    /// Parse a single unified patch
+    ///
+    /// Fails with `Error::BinaryFiles` if the patch is for binary files.
+    pub fn parse_patch<'a, I>(iter_lines: I, allow_dirty: bool) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a [u8]> + 'a,
+    {
+        Self::parse_patch_with_options(iter_lines, allow_dirty, &ParseOptions::default())
+    }
+
+    /// Parse a single unified patch, enforcing the limits in `options`
+    ///
+    /// Fails with `Error::LimitExceeded` if the patch exceeds any of the limits.
+    pub fn parse_patch_with_options<'a, I>(
+        iter_lines: I,
+        allow_dirty: bool,
+        options: &ParseOptions,
+    ) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a [u8]> + 'a,
+    {
+        if options.normalize_crlf {
+            let mut crlf = false;
+            let lines = iter_lines
+                .map(|line| match line.strip_suffix(b"\r\n") {
+                    Some(line) => {
+                        crlf = true;
+                        [line, b"\n"].concat()
+                    }
+                    None => line.to_vec(),
+                })
+                .collect::<Vec<_>>();
+            let mut patch = Self::parse_with_limits(
+                lines.iter().map(|line| line.as_slice()),
+                allow_dirty,
+                options,
+            )?;
+            patch.crlf = crlf;
+            return Ok(patch);
+        }
+        Self::parse_with_limits(iter_lines, allow_dirty, options)
+    }
+
+    fn parse_with_limits<'a, I>(
+        iter_lines: I,
+        allow_dirty: bool,
+        options: &ParseOptions,
+    ) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a [u8]> + 'a,
+    {
+        // Stop reading at the first overlong line, and report that rather than
+        // whatever error the truncated input causes
+        let too_long = std::rc::Rc::new(std::cell::Cell::new(false));
+        let iter_lines = match options.max_line_len {
+            Some(max_line_len) => {
+                let too_long = too_long.clone();
+                Box::new(iter_lines.take_while(move |line| {
+                    too_long.set(line.len() > max_line_len);
+                    !too_long.get()
+                })) as Box<dyn Iterator<Item = &'a [u8]> + 'a>
+            }
+            None => Box::new(iter_lines),
+        };
+        let ret = Self::parse_limited(iter_lines, allow_dirty, options.max_hunks);
+        if too_long.get() {
+            return Err(Error::LimitExceeded("line length", options.max_line_len.unwrap()));
+        }
+        ret
+    }
+
+    fn parse_limited<'a, I>(iter_lines: I, allow_dirty: bool, max_hunks: Option<usize>) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a [u8]> + 'a,
+    {
+        let mut iter_lines = iter_lines_handle_nl(iter_lines);
+
+        let mut raw_header = Vec::new();
+        let mut warnings = Vec::new();
+        let ((orig_name, orig_ts), (mod_name, mod_ts)) = {
+            let mut header_lines = (&mut iter_lines).inspect(|line| raw_header.extend_from_slice(line));
+            get_patch_names(&mut header_lines, &mut warnings)?
+        };
+
+        let mut patch = UnifiedPatch::new(orig_name, orig_ts, mod_name, mod_ts);
+        patch.raw_header = Some(raw_header);
+        patch.warnings = warnings;
+        for hunk in iter_hunks(&mut iter_lines, allow_dirty) {
+            if max_hunks.is_some_and(|max_hunks| patch.hunks.len() >= max_hunks) {
+                return Err(Error::LimitExceeded("number of hunks", max_hunks.unwrap()));
+            }
+            patch.hunks.push(hunk?);
+        }
+        Ok(patch)
+    }
+}
+
+/// Limits to enforce while parsing, to guard against hostile input
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Maximum length of a line, in bytes, including its line ending
+    pub max_line_len: Option<usize>,
+
+    /// Maximum number of hunks in a patch
+    pub max_hunks: Option<usize>,
+
+    /// Convert `\r\n` line endings to `\n` before parsing, so that a patch made on
+    /// Windows applies to files with Unix line endings; the patch's `crlf` flag
+    /// records whether any were found
+    pub normalize_crlf: bool,
+}
+
+#[cfg(test)]
+mod parse_options_tests {
+    use super::{splitlines, Error, ParseOptions, UnifiedPatch};
+
+    const PATCH: &[u8] = b"--- a\n+++ a\n@@ -1 +1 @@\n-x\n+a rather long line\n@@ -5 +5 @@\n-y\n+z\n";
+
+    #[test]
+    fn test_max_line_len() {
+        let options = ParseOptions { max_line_len: Some(10), ..Default::default() };
+        assert_eq!(
+            UnifiedPatch::parse_patch_with_options(splitlines(PATCH), false, &options).err(),
+            Some(Error::LimitExceeded("line length", 10))
+        );
+        let options = ParseOptions { max_line_len: Some(20), ..Default::default() };
+        assert!(UnifiedPatch::parse_patch_with_options(splitlines(PATCH), false, &options).is_ok());
+    }
+
+    #[test]
+    fn test_normalize_crlf() {
+        use crate::patch::Patch;
+        let data = b"--- a\r\n+++ a\r\n@@ -1,2 +1,2 @@\r\n a\r\n-b\n+B\r\n";
+
+        // By default the line endings are kept, so the patch does not apply to a
+        // file with Unix line endings
+        let patch = UnifiedPatch::parse_patch(splitlines(data), false);
+        assert!(patch.is_err() || patch.unwrap().apply_exact(b"a\nb\n").is_err());
+
+        let options = ParseOptions { normalize_crlf: true, ..Default::default() };
+        let patch = UnifiedPatch::parse_patch_with_options(splitlines(data), false, &options).unwrap();
+        assert!(patch.crlf);
+        assert_eq!(patch.orig_name, b"a");
+        assert_eq!(patch.apply_exact(b"a\nb\n").unwrap(), b"a\nB\n");
+        assert_eq!(
+            patch.as_bytes(),
+            b"--- a\r\n+++ a\r\n@@ -1,2 +1,2 @@\r\n a\r\n-b\r\n+B\r\n"
+        );
+    }
+
+    #[test]
+    fn test_max_hunks() {
+        let options = ParseOptions { max_hunks: Some(1), ..Default::default() };
+        assert_eq!(
+            UnifiedPatch::parse_patch_with_options(splitlines(PATCH), false, &options).err(),
+            Some(Error::LimitExceeded("number of hunks", 1))
+        );
+        let options = ParseOptions { max_hunks: Some(2), ..Default::default() };
+        assert_eq!(
+            UnifiedPatch::parse_patch_with_options(splitlines(PATCH), false, &options)
+                .unwrap()
+                .hunks
+                .len(),
+            2
+        );
+    }
+}
+
+#[cfg(test)]
+mod raw_header_tests {
+    use super::{splitlines, UnifiedPatch};
+
+    #[test]
+    fn test_roundtrip_unusual_header() {
+        let data = b"+++  foo.c\t  2009-10-14 19:49:59 +0000 \n--- foo.c \n@@ -1 +1 @@\n-x\n+y\n";
+        let mut patch = UnifiedPatch::parse_patch(splitlines(data), false).unwrap();
+        assert_eq!(patch.as_bytes(), data);
+
+        // Without the raw header, the lines are rebuilt in the usual order
+        patch.raw_header = None;
+        assert_eq!(
+            patch.as_bytes(),
+            b"--- foo.c \n+++  foo.c\t  2009-10-14 19:49:59 +0000 \n@@ -1 +1 @@\n-x\n+y\n"
+        );
+    }
+
+    #[test]
+    fn test_stale_after_rename() {
+        let data = b"--- foo \n+++ foo \n@@ -1 +1 @@\n-x\n+y\n";
+        let mut patch = UnifiedPatch::parse_patch(splitlines(data), false).unwrap();
+        patch.mod_name = b"bar".to_vec();
+        patch.mod_ts = Some(b"2009-10-14 19:49:59 +0000".to_vec());
+        assert_eq!(
+            patch.as_bytes(),
+            b"--- foo \n+++ bar\t2009-10-14 19:49:59 +0000\n@@ -1 +1 @@\n-x\n+y\n"
+        );
+    }
+
+    #[test]
+    fn test_prefix_paths_clears() {
+        let data = b"--- foo\n+++ foo\n@@ -1 +1 @@\n-x\n+y\n";
+        let mut patch = UnifiedPatch::parse_patch(splitlines(data), false).unwrap();
+        patch.prefix_paths(b"sub/");
+        assert_eq!(&patch.as_bytes()[..24], b"--- sub/foo\n+++ sub/foo\n");
+    }
+}
+
+pub fn parse_patch<'a, I>(iter_lines: I, allow_dirty: bool) -> Result<Box<dyn Patch>, Error>
+where
+    I: Iterator<Item = &'a [u8]> + 'a,
+{
+    match UnifiedPatch::parse_patch(iter_lines, allow_dirty) {
+        Ok(patch) => Ok(Box::new(patch)),
+        Err(Error::BinaryFiles(orig_name, mod_name)) => {
+            Ok(Box::new(BinaryPatch(orig_name, mod_name)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Detect the format of a single-file patch, and parse it accordingly
+///
+/// Unified and context diffs are supported. Any text before the file headers is
+/// skipped.
+///
+/// # Returns
+/// The detected format along with the parsed patch. Formats that can not be
+/// applied, such as ed scripts and normal diffs, are reported as
+/// `Error::UnsupportedFormat`.
+pub fn detect_and_parse(data: &[u8]) -> Result<(DiffFormat, Box<dyn Patch>), Error> {
+    let format = sniff_format(data).ok_or_else(|| Error::PatchSyntax("Not a diff", vec![]))?;
+    let patch: Box<dyn Patch> = match format {
+        DiffFormat::Unified => {
+            let lines = splitlines(data).skip_while(|line| {
+                !(line.starts_with(b"--- ")
+                    || line.starts_with(b"+++ ")
+                    || BINARY_FILES_RE.is_match(line))
+            });
+            parse_patch(lines, true)?
+        }
+        DiffFormat::Context => Box::new(crate::context::ContextPatch::parse(splitlines(data))?),
+        DiffFormat::Ed | DiffFormat::Normal => return Err(Error::UnsupportedFormat(format)),
+    };
+    Ok((format, patch))
+}
+
+#[cfg(test)]
+mod detect_and_parse_tests {
+    use super::{detect_and_parse, DiffFormat, Error};
+
+    #[test]
+    fn test_unified() {
+        let (format, patch) =
+            detect_and_parse(b"Some text\n--- a\n+++ a\n@@ -1 +1 @@\n-x\n+y\n").unwrap();
+        assert_eq!(format, DiffFormat::Unified);
+        assert_eq!(patch.apply_exact(b"x\n").unwrap(), b"y\n");
+    }
+
+    #[test]
+    fn test_context() {
+        let (format, patch) =
+            detect_and_parse(include_bytes!("../test_patches_data/context-3")).unwrap();
+        assert_eq!(format, DiffFormat::Context);
+        assert_eq!(patch.oldname(), b"orig-3");
+        assert_eq!(
+            patch.apply_exact(include_bytes!("../test_patches_data/orig-3")).unwrap(),
+            include_bytes!("../test_patches_data/mod-3")
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert_eq!(
+            detect_and_parse(b"2c2\n< x\n---\n> y\n").err(),
+            Some(Error::UnsupportedFormat(DiffFormat::Normal))
+        );
+        assert!(matches!(detect_and_parse(b"hello\n"), Err(Error::PatchSyntax(..))));
+    }
+}
+
+/// Parse a multi-file patch as produced by `svn diff`
+///
+/// Each file in such a patch is introduced by an `Index: path` line and a line of `=`
+/// characters. The `Index:` line is taken as the authoritative file name, since the
+/// `---`/`+++` lines often carry revision annotations or other paths.
+///
+/// Anything before the first `Index:` line is ignored.
+pub fn parse_svn_patches(data: &[u8]) -> Vec<Result<UnifiedPatch, Error>> {
+    let mut sections: Vec<(&[u8], Vec<&[u8]>)> = Vec::new();
+    for line in splitlines(data) {
+        if let Some(path) = line.strip_prefix(b"Index: ") {
+            let path = path.strip_suffix(b"\n").unwrap_or(path);
+            let path = path.strip_suffix(b"\r").unwrap_or(path);
+            sections.push((path, Vec::new()));
+        } else if let Some((_, lines)) = sections.last_mut() {
+            if lines.is_empty() && line.starts_with(b"====") {
+                continue;
+            }
+            lines.push(line);
+        }
+    }
+
+    sections
+        .into_iter()
+        .map(|(path, lines)| {
+            let mut patch = UnifiedPatch::parse_patch(lines.into_iter(), true)?;
+            patch.orig_name = path.to_vec();
+            patch.mod_name = path.to_vec();
+            patch.raw_header = None;
+            Ok(patch)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod parse_svn_patches_tests {
+    #[test]
+    fn test_two_files() {
+        let data = b"Index: trunk/foo.c
+===================================================================
+--- foo.c\t(revision 12)
++++ foo.c\t(working copy)
+@@ -1 +1 @@
+-old
++new
+Index: trunk/bar.c
+===================================================================
+--- bar.c\t(revision 12)
++++ bar.c\t(working copy)
+@@ -1,2 +1,3 @@
+ a
++b
+ c
+";
+        let patches = super::parse_svn_patches(data)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].orig_name, b"trunk/foo.c");
+        assert_eq!(patches[0].mod_name, b"trunk/foo.c");
+        assert_eq!(patches[0].orig_ts, Some(b"(revision 12)".to_vec()));
+        assert_eq!(patches[0].hunks.len(), 1);
+        assert_eq!(patches[1].orig_name, b"trunk/bar.c");
+        assert_eq!(patches[1].hunks[0].lines.len(), 3);
+    }
+
+    #[test]
+    fn test_missing_header() {
+        let data = b"Index: foo.bin
+===================================================================
+Cannot display: file marked as a binary type.
+";
+        let patches = super::parse_svn_patches(data);
+        assert_eq!(patches.len(), 1);
+        assert!(patches[0].is_err());
+    }
+}
+
+
+/// Iterate over the lines of a patch that are not part of a hunk, along with
+/// their offsets in `data`
+///
+/// Hunk line counts are tracked so that lines in a hunk that look like something
+/// else, such as removed lines starting with `--`, are skipped along with the hunk
+/// headers themselves.
+fn lines_outside_hunks(data: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut offset = 0;
+    let mut orig_left = 0;
+    let mut mod_left = 0;
+    splitlines(data).filter_map(move |line| {
+        let line_start = offset;
+        offset += line.len();
+        if orig_left > 0 || mod_left > 0 {
+            match line.first() {
+                Some(b' ') | Some(b'\n') => {
+                    orig_left -= usize::from(orig_left > 0);
+                    mod_left -= usize::from(mod_left > 0);
+                    return None;
+                }
+                Some(b'-') if orig_left > 0 => {
+                    orig_left -= 1;
+                    return None;
+                }
+                Some(b'+') if mod_left > 0 => {
+                    mod_left -= 1;
+                    return None;
+                }
+                Some(b'\\') => return None,
+                // The hunk ended early; treat the line as being outside of it
+                _ => {
+                    orig_left = 0;
+                    mod_left = 0;
+                }
+            }
+        }
+        if line.starts_with(b"@@") {
+            if let Ok(hunk) = Hunk::from_header(line) {
+                orig_left = hunk.orig_range;
+                mod_left = hunk.mod_range;
+                return None;
+            }
+        }
+        Some((line_start, line))
+    })
+}
+
+/// Find the byte ranges of the sections for each file in a multi-file patch
+///
+/// Each section starts with the header lines for a file (such as `diff --git`,
+/// `Index:` or `---`) and extends up to the start of the next file's section. Any
+/// leading text before the first file is included in the first section, so the
+/// returned ranges are contiguous and together cover all of `data`.
+pub fn file_patch_spans(data: &[u8]) -> Vec<std::ops::Range<usize>> {
+    let mut starts = Vec::new();
+    // Whether the current section has had its `---` (or `Binary files`) line yet
+    let mut seen_header = false;
+    // Whether the current section has had a `diff --git` line yet; git omits the
+    // `---` line for renames, mode changes and empty files
+    let mut seen_git_diff = false;
+    if !data.is_empty() {
+        starts.push(0);
+    }
+    for (line_start, line) in lines_outside_hunks(data) {
+        if line.starts_with(b"--- ") || BINARY_FILES_RE.is_match(line) {
+            if seen_header {
+                starts.push(line_start);
+                seen_git_diff = false;
+            }
+            seen_header = true;
+        } else if (line.starts_with(b"diff ")
+            || line.starts_with(b"Index: ")
+            || line.starts_with(b"=== "))
+            && (seen_header || (seen_git_diff && line.starts_with(b"diff --git ")))
+        {
+            starts.push(line_start);
+            seen_header = false;
+            seen_git_diff = line.starts_with(b"diff --git ");
+        } else if line.starts_with(b"diff --git ") {
+            seen_git_diff = true;
+        }
+    }
+    let mut ends = starts.iter().skip(1).copied().collect::<Vec<_>>();
+    if !starts.is_empty() {
+        ends.push(data.len());
+    }
+    starts.into_iter().zip(ends).map(|(start, end)| start..end).collect()
+}
+
+/// Iterate over the sections for each file in a multi-file patch
+///
+/// See `file_patch_spans` for how the sections are delimited.
+pub fn iter_file_patch(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    file_patch_spans(data).into_iter().map(move |span| &data[span])
+}
+
+/// Split a multi-file patch into the raw bytes of each file's section
+///
+/// The bytes are sliced out of `data` as-is rather than re-serialized, so nothing
+/// is lost in the process.
+pub fn split_patch_file(data: &[u8]) -> Vec<Vec<u8>> {
+    iter_file_patch(data).map(|section| section.to_vec()).collect()
+}
+
+/// Parse the section for a single file, skipping any lines before its `---` header
+///
+/// git's extended header lines are recorded in the patch's `git` field. A git
+/// section without a `---` header (such as a pure rename or mode change, or an
+/// empty new file) is named after its `diff --git` line.
+fn parse_file_section(section: &[u8], allow_dirty: bool) -> Result<UnifiedPatch, Error> {
+    let mut lines = splitlines(section).peekable();
+    let mut preamble = Vec::new();
+    while let Some(line) = lines.peek() {
+        if line.starts_with(b"--- ") || BINARY_FILES_RE.is_match(line) {
+            break;
+        }
+        preamble.push(*line);
+        lines.next();
+    }
+    let git = parse_git_metadata(&preamble);
+    match git {
+        Some(git) if lines.peek().is_none() => {
+            let orig_name = if git.new_file { crate::patch::DEV_NULL.to_vec() } else { git.old_name.clone() };
+            let mod_name = if git.deleted_file { crate::patch::DEV_NULL.to_vec() } else { git.new_name.clone() };
+            let mut patch = UnifiedPatch::new(orig_name, None, mod_name, None);
+            patch.git = Some(git);
+            Ok(patch)
+        }
+        git => {
+            let mut patch = UnifiedPatch::parse_patch(lines, allow_dirty)?;
+            patch.git = git;
+            Ok(patch)
+        }
+    }
+}
+
+/// Parse git's extended header lines, starting at the `diff --git` line
+///
+/// Lines before the `diff --git` line and unrecognized lines are ignored.
+///
+/// # Returns
+/// The metadata, or `None` if there is no `diff --git` line
+pub fn parse_git_metadata(lines: &[&[u8]]) -> Option<crate::patch::GitMetadata> {
+    let start = lines.iter().position(|line| line.starts_with(b"diff --git "))?;
+    let (old_name, new_name) = parse_git_diff_header(lines[start])?;
+    let mut git = crate::patch::GitMetadata { old_name, new_name, ..Default::default() };
+    let mode = |value: &[u8]| u32::from_str_radix(std::str::from_utf8(value).ok()?, 8).ok();
+    for line in &lines[start + 1..] {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if let Some(value) = line.strip_prefix(b"old mode ") {
+            git.old_mode = mode(value);
+        } else if let Some(value) = line.strip_prefix(b"new mode ") {
+            git.new_mode = mode(value);
+        } else if let Some(value) = line.strip_prefix(b"new file mode ") {
+            git.new_file = true;
+            git.new_mode = mode(value);
+        } else if let Some(value) = line.strip_prefix(b"deleted file mode ") {
+            git.deleted_file = true;
+            git.old_mode = mode(value);
+        } else if let Some(value) = line.strip_prefix(b"rename from ") {
+            git.rename_from = unquote_path(value);
+        } else if let Some(value) = line.strip_prefix(b"rename to ") {
+            git.rename_to = unquote_path(value);
+        } else if let Some(value) = line.strip_prefix(b"copy from ") {
+            git.copy_from = unquote_path(value);
+        } else if let Some(value) = line.strip_prefix(b"copy to ") {
+            git.copy_to = unquote_path(value);
+        } else if let Some(value) = line.strip_prefix(b"similarity index ") {
+            git.similarity = std::str::from_utf8(value.strip_suffix(b"%").unwrap_or(value))
+                .ok()
+                .and_then(|value| value.parse().ok());
+        } else if let Some(value) = line.strip_prefix(b"index ") {
+            // index <old>..<new>[ <mode>]
+            let mut parts = value.splitn(2, |&c| c == b' ');
+            let hashes = parts.next().unwrap_or_default();
+            if let Some(i) = hashes.windows(2).position(|w| w == b"..") {
+                git.old_hash = Some(hashes[..i].to_vec());
+                git.new_hash = Some(hashes[i + 2..].to_vec());
+            }
+            if let Some(value) = parts.next().and_then(mode) {
+                git.old_mode = Some(value);
+                git.new_mode = Some(value);
+            }
+        }
+    }
+    Some(git)
+}
+
+#[cfg(test)]
+mod git_metadata_tests {
+    use super::parse_patches_spanned;
+
+    const GIT_DIFF: &[u8] = b"diff --git a/foo.c b/foo.c
+index 1234567..89abcde 100644
+--- a/foo.c
++++ b/foo.c
+@@ -1 +1 @@
+-a
++b
+diff --git a/old.txt b/new.txt
+similarity index 90%
+rename from old.txt
+rename to new.txt
+diff --git a/run.sh b/run.sh
+old mode 100644
+new mode 100755
+diff --git a/empty b/empty
+new file mode 100644
+index 0000000..e69de29
+diff --git a/added b/added
+new file mode 100644
+index 0000000..7898192
+--- /dev/null
++++ b/added
+@@ -0,0 +1 @@
++a
+";
+
+    #[test]
+    fn test_git_metadata() {
+        let patches = parse_patches_spanned(GIT_DIFF)
+            .into_iter()
+            .map(|(_, patch)| patch.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(patches.len(), 5);
+
+        let git = patches[0].git.as_ref().unwrap();
+        assert_eq!(git.old_hash, Some(b"1234567".to_vec()));
+        assert_eq!(git.new_hash, Some(b"89abcde".to_vec()));
+        assert_eq!((git.old_mode, git.new_mode), (Some(0o100644), Some(0o100644)));
+        assert_eq!(patches[0].hunks.len(), 1);
+
+        let git = patches[1].git.as_ref().unwrap();
+        assert_eq!(git.rename_from, Some(b"old.txt".to_vec()));
+        assert_eq!(git.rename_to, Some(b"new.txt".to_vec()));
+        assert_eq!(git.similarity, Some(90));
+        assert_eq!(patches[1].orig_name, b"a/old.txt");
+        assert_eq!(patches[1].mod_name, b"b/new.txt");
+        assert!(patches[1].hunks.is_empty());
+
+        let git = patches[2].git.as_ref().unwrap();
+        assert_eq!((git.old_mode, git.new_mode), (Some(0o100644), Some(0o100755)));
+
+        assert!(patches[3].git.as_ref().unwrap().new_file);
+        assert_eq!(patches[3].orig_name, b"/dev/null");
+        assert_eq!(patches[3].mod_name, b"b/empty");
+
+        assert!(patches[4].git.as_ref().unwrap().new_file);
+        assert_eq!(patches[4].orig_name, b"/dev/null");
+        assert_eq!(patches[4].hunks.len(), 1);
+    }
+
+    #[test]
+    fn test_plain_patch() {
+        let patches = parse_patches_spanned(b"--- a/foo\n+++ b/foo\n@@ -1 +1 @@\n-a\n+b\n");
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].1.as_ref().unwrap().git, None);
+    }
+}
+
+/// What a patch in a patch set does to its file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileOperation {
+    /// The file is created
+    Added,
+
+    /// The file is deleted
+    Deleted,
+
+    /// The file is changed in place
+    Modified,
+
+    /// The file is renamed, and possibly changed
+    Renamed { from: Vec<u8>, to: Vec<u8> },
+
+    /// The file is a copy of another file, and possibly changed
+    CopiedFrom { from: Vec<u8>, to: Vec<u8> },
+}
+
+/// The changes to a single file in a patch set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePatch {
+    /// What happens to the file
+    pub operation: FileOperation,
+
+    /// The changes to the contents of the file, or `None` if there are no hunks,
+    /// as for a pure rename or a binary file
+    pub patch: Option<UnifiedPatch>,
+}
+
+fn file_operation(
+    git: Option<&crate::patch::GitMetadata>,
+    orig_name: &[u8],
+    mod_name: &[u8],
+) -> FileOperation {
+    if let Some(git) = git {
+        if let (Some(from), Some(to)) = (&git.rename_from, &git.rename_to) {
+            return FileOperation::Renamed { from: from.clone(), to: to.clone() };
+        }
+        if let (Some(from), Some(to)) = (&git.copy_from, &git.copy_to) {
+            return FileOperation::CopiedFrom { from: from.clone(), to: to.clone() };
+        }
+        if git.new_file {
+            return FileOperation::Added;
+        }
+        if git.deleted_file {
+            return FileOperation::Deleted;
+        }
+    }
+    if orig_name == crate::patch::DEV_NULL {
+        FileOperation::Added
+    } else if mod_name == crate::patch::DEV_NULL {
+        FileOperation::Deleted
+    } else {
+        FileOperation::Modified
+    }
+}
+
+/// Parse a multi-file git patch, such as the output of `git diff` or
+/// `git format-patch`, into the operation performed on each file
+///
+/// Renames, copies and mode changes without any hunks are still reported, with
+/// no patch. Binary files are reported the same way.
+pub fn parse_git_patch_set(data: &[u8]) -> Result<Vec<FilePatch>, Error> {
+    iter_file_patch(data)
+        .map(|section| match parse_file_section(section, true) {
+            Ok(patch) => {
+                let operation =
+                    file_operation(patch.git.as_ref(), &patch.orig_name, &patch.mod_name);
+                let patch = if patch.hunks.is_empty() { None } else { Some(patch) };
+                Ok(FilePatch { operation, patch })
+            }
+            Err(Error::BinaryFiles(orig_name, mod_name)) => {
+                let lines = splitlines(section).collect::<Vec<_>>();
+                let git = parse_git_metadata(&lines);
+                let operation = file_operation(git.as_ref(), &orig_name, &mod_name);
+                Ok(FilePatch { operation, patch: None })
+            }
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod git_patch_set_tests {
+    use super::{parse_git_patch_set, FileOperation};
+
+    #[test]
+    fn test_patch_set() {
+        let data = b"From 1234 Mon Sep 17 00:00:00 2001
+Subject: [PATCH] Shuffle files
+
+---
+diff --git a/foo.c b/foo.c
+index 1234567..89abcde 100644
+--- a/foo.c
++++ b/foo.c
+@@ -1 +1 @@
+-a
++b
+diff --git a/old.txt b/new.txt
+similarity index 100%
+rename from old.txt
+rename to new.txt
+diff --git a/foo.c b/bar.c
+similarity index 90%
+copy from foo.c
+copy to bar.c
+--- a/foo.c
++++ b/bar.c
+@@ -1 +1 @@
+-a
++c
+diff --git a/gone b/gone
+deleted file mode 100644
+index 7898192..0000000
+--- a/gone
++++ /dev/null
+@@ -1 +0,0 @@
+-a
+diff --git a/logo.png b/logo.png
+new file mode 100644
+index 0000000..1234567
+Binary files /dev/null and b/logo.png differ
+-- 
+2.39.2
+";
+        let files = parse_git_patch_set(data).unwrap();
+        let operations = files.iter().map(|f| f.operation.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            operations,
+            vec![
+                FileOperation::Modified,
+                FileOperation::Renamed { from: b"old.txt".to_vec(), to: b"new.txt".to_vec() },
+                FileOperation::CopiedFrom { from: b"foo.c".to_vec(), to: b"bar.c".to_vec() },
+                FileOperation::Deleted,
+                FileOperation::Added,
+            ]
+        );
+        let hunks = files.iter().map(|f| f.patch.as_ref().map(|p| p.hunks.len())).collect::<Vec<_>>();
+        assert_eq!(hunks, vec![Some(1), None, Some(1), Some(1), None]);
+    }
+}
+
+/// List the files touched by a multi-file patch, without parsing its hunks
+///
+/// Only the `---`/`+++` and `Binary files` headers of each file are looked at, so
+/// this is much cheaper than parsing the whole patch. Sections without a valid
+/// header are skipped.
+///
+/// # Returns
+/// The old and new name of each file, in the order they appear in the patch
+pub fn list_files(data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    iter_file_patch(data)
+        .filter_map(|section| {
+            let mut lines = splitlines(section)
+                .skip_while(|line| !line.starts_with(b"--- ") && !BINARY_FILES_RE.is_match(line));
+            match get_patch_names(&mut lines, &mut Vec::new()) {
+                Ok(((orig_name, _), (mod_name, _))) => Some((orig_name, mod_name)),
+                Err(Error::BinaryFiles(orig_name, mod_name)) => Some((orig_name, mod_name)),
+                Err(_) => None,
             }
-        }
-        last_line.take()
-    })
+        })
+        .collect()
 }
 
-#[test]
-fn test_iter_lines_handle_nl() {
-    let lines = vec![
-        &b"line 1\n"[..],
-        &b"line 2\n"[..],
-        &b"line 3\n"[..],
-        &b"line 4\n"[..],
-        &b"\\ No newline at end of file\n"[..]
-    ];
-    let mut iter = iter_lines_handle_nl(lines.into_iter());
-    assert_eq!(iter.next(), Some("line 1\n".as_bytes()));
-    assert_eq!(iter.next(), Some("line 2\n".as_bytes()));
-    assert_eq!(iter.next(), Some("line 3\n".as_bytes()));
-    assert_eq!(iter.next(), Some("line 4".as_bytes()));
-    assert_eq!(iter.next(), None);
+#[cfg(test)]
+mod list_files_tests {
+    #[test]
+    fn test_list_files() {
+        let data = b"diff --git a/foo b/foo
+--- a/foo
++++ b/foo
+@@ -1 +1 @@
+--- not a header
++++ nor is this
+diff --git a/logo.png b/logo.png
+Binary files a/logo.png and b/logo.png differ
+--- /dev/null	2009-10-14 19:49:59 +0000
++++ b/new	2009-10-14 19:49:59 +0000
+@@ -0,0 +1 @@
++x
+";
+        assert_eq!(
+            super::list_files(data),
+            vec![
+                (b"a/foo".to_vec(), b"b/foo".to_vec()),
+                (b"a/logo.png".to_vec(), b"b/logo.png".to_vec()),
+                (b"/dev/null".to_vec(), b"b/new".to_vec()),
+            ]
+        );
+    }
 }
 
-static BINARY_FILES_RE: once_cell::sync::Lazy<regex::bytes::Regex> = lazy_regex::bytes_lazy_regex!(r"^Binary files (.+) and (.+) differ");
+/// Parse a multi-file patch, returning each file's patch along with the range
+/// of bytes in `data` it was parsed from
+///
+/// Trailing junk after the last hunk of a file is ignored. Binary files are
+/// reported as `Error::BinaryFiles`.
+pub fn parse_patches_spanned(
+    data: &[u8],
+) -> Vec<(std::ops::Range<usize>, Result<UnifiedPatch, Error>)> {
+    file_patch_spans(data)
+        .into_iter()
+        .map(|span| {
+            let patch = parse_file_section(&data[span.clone()], true);
+            (span, patch)
+        })
+        .collect()
+}
 
-fn get_patch_names<'a, T: Iterator<Item = &'a [u8]>>(
-    iter_lines: &mut T,
-) -> Result<((Vec<u8>, Option<Vec<u8>>), (Vec<u8>, Option<Vec<u8>>)), Error> {
-    let line = iter_lines
-        .next()
-        .ok_or_else(|| Error::PatchSyntax("No input", vec![]))?;
+/// Check every file section of a multi-file patch, reporting all that fail to parse
+///
+/// Unlike parsing, this does not stop at the first error, so a whole patch can be
+/// diagnosed at once. Binary file sections are not considered errors, and
+/// neither is text after the last hunk of a file (such as the signature
+/// `git format-patch` appends), as long as it contains no further hunk headers.
+///
+/// # Returns
+/// The index of each failing file section, along with its error
+pub fn validate_patches(data: &[u8]) -> Vec<(usize, Error)> {
+    iter_file_patch(data)
+        .enumerate()
+        .filter_map(|(i, section)| match parse_file_section(section, true) {
+            Err(Error::BinaryFiles(..)) => None,
+            Err(e) => Some((i, e)),
+            // Hunk lines never start with "@", so any such line is a hunk header
+            Ok(patch)
+                if splitlines(section).filter(|line| line.starts_with(b"@@")).count()
+                    == patch.hunks.len() =>
+            {
+                None
+            }
+            // Some hunks were cut off by junk; the strict parse reports where
+            Ok(_) => parse_file_section(section, false).err().map(|e| (i, e)),
+        })
+        .collect()
+}
 
-    if let Some(captures) = BINARY_FILES_RE.captures(line) {
-        let orig_name = captures.get(1).unwrap().as_bytes().to_vec();
-        let mod_name = captures.get(2).unwrap().as_bytes().to_vec();
-        return Err(Error::BinaryFiles(orig_name, mod_name));
+#[cfg(test)]
+mod validate_patches_tests {
+    #[test]
+    fn test_page_break() {
+        let data = b"--- a\n+++ a\n@@ -1 +1 @@\n-x\n+y\n\x0c\n--- b\n+++ b\n@@ -1 +1 @@\n-x\n+y\n\x0c";
+        assert_eq!(super::validate_patches(data), vec![]);
+        let patches = super::parse_patches_spanned(data);
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[1].1.as_ref().unwrap().orig_name, b"b");
     }
-    let orig_name = line
-        .strip_prefix(b"--- ")
-        .ok_or_else(|| Error::MalformedPatchHeader("No orig name", line.to_vec()))?
-        .strip_suffix(b"\n")
-        .ok_or_else(|| Error::PatchSyntax("missing newline", line.to_vec()))?;
-    let (orig_name, orig_ts) = match orig_name.split(|&c| c == b'\t').collect::<Vec<_>>()[..]
-    {
-        [name, ts] => (name.to_vec(), Some(ts.to_vec())),
-        [name] => (name.to_vec(), None),
-        _ => return Err(Error::MalformedPatchHeader("No orig line", line.to_vec())),
-    };
 
-    let line = iter_lines
-        .next()
-        .ok_or_else(|| Error::PatchSyntax("No input", vec![]))?;
+    #[test]
+    fn test_format_patch_signature() {
+        let data = b"From 1234 Mon Sep 17 00:00:00 2001
+From: Jane <jane@example.com>
+Subject: [PATCH] Change x
 
-    let (mod_name, mod_ts) = match line.strip_prefix(b"+++ ") {
-        Some(line) => {
-            let mod_name = line
-                .strip_suffix(b"\n")
-                .ok_or_else(|| Error::PatchSyntax("missing newline", line.to_vec()))?;
-            let (mod_name, mod_ts) = match mod_name.split(|&c| c == b'\t').collect::<Vec<_>>()[..] {
-                [name, ts] => (name.to_vec(), Some(ts.to_vec())),
-                [name] => (name.to_vec(), None),
-                _ => return Err(Error::PatchSyntax("Invalid mod name", line.to_vec())),
-            };
-            (mod_name, mod_ts)
-        }
-        None => return Err(Error::MalformedPatchHeader("No mod line", line.to_vec())),
-    };
+---
+ a | 2 +-
+ 1 file changed, 1 insertion(+), 1 deletion(-)
 
-    Ok(((orig_name, orig_ts), (mod_name, mod_ts)))
-}
+diff --git a/a b/a
+index 1111111..2222222 100644
+--- a/a
++++ b/a
+@@ -1 +1 @@
+-x
++y
+-- 
+2.39.2
+
+";
+        assert_eq!(super::validate_patches(data), vec![]);
+    }
 
-#[cfg(test)]
-mod get_patch_names_tests {
     #[test]
-    fn test_simple() {
-        let lines = [
-            &b"--- baz	2009-10-14 19:49:59 +0000\n"[..],
-            &b"+++ quxx	2009-10-14 19:51:00 +0000\n"[..]];
-        let mut iter = lines.into_iter();
-        let (old, new) = super::get_patch_names(&mut iter).unwrap();
-        assert_eq!(old, (b"baz".to_vec(), Some(b"2009-10-14 19:49:59 +0000".to_vec())));
-        assert_eq!(new, (b"quxx".to_vec(), Some(b"2009-10-14 19:51:00 +0000".to_vec())));
+    fn test_junk_between_hunks() {
+        let data = b"--- a\n+++ a\n@@ -1 +1 @@\n-x\n+y\njunk\n@@ -3 +3 @@\n-x\n+y\n";
+        assert_eq!(
+            super::validate_patches(data),
+            vec![(0, super::Error::MalformedHunkHeader("Does not match format.", b"junk\n".to_vec()))]
+        );
     }
 
     #[test]
-    fn test_binary() {
-        let lines = [
-            &b"Binary files qoo and bar differ\n"[..]
-        ];
-        let mut iter = lines.into_iter();
-        let e = super::get_patch_names(&mut iter).unwrap_err();
-        assert_eq!(e, super::Error::BinaryFiles(b"qoo".to_vec(), b"bar".to_vec()));
+    fn test_one_malformed() {
+        let data = b"--- a
++++ a
+@@ -1 +1 @@
+-x
++y
+--- b
++++ b
+@@ -1 +1 @@
+-x
+?y
+--- c
++++ c
+@@ -1 +1 @@
+-x
++y
+";
+        let errors = super::validate_patches(data);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 1);
+        assert_eq!(
+            errors[0].1,
+            super::Error::PatchSyntax("Invalid hunk line", b"?y\n".to_vec())
+        );
     }
-}
 
-pub fn iter_hunks<'a, I>(
-    iter_lines: &mut I,
-    allow_dirty: bool,
-) -> impl Iterator<Item = Result<Hunk, Error>> + '_
-where
-    I: Iterator<Item = &'a [u8]>
-{
-    std::iter::from_fn(move || {
-        while let Some(line) = iter_lines.next() {
-            if line == b"\n" {
-                continue;
-            }
-            match Hunk::from_header(line) {
-                Ok(mut new_hunk) => {
-                    let mut orig_size = 0;
-                    let mut mod_size = 0;
-                    while orig_size < new_hunk.orig_range || mod_size < new_hunk.mod_range {
-                        let line = iter_lines.next()?;
-                        match HunkLine::parse_line(line) {
-                            Err(_) => {
-                                return Some(Err(Error::PatchSyntax("Invalid hunk line", line.to_vec())));
-                            }
-                            Ok(hunk_line) => {
-                                if matches!(
-                                    hunk_line,
-                                    HunkLine::RemoveLine(_) | HunkLine::ContextLine(_)
-                                ) {
-                                    orig_size += 1
-                                }
-                                if matches!(
-                                    hunk_line,
-                                    HunkLine::InsertLine(_) | HunkLine::ContextLine(_)
-                                ) {
-                                    mod_size += 1
-                                }
-                                new_hunk.lines.push(hunk_line);
-                            }
-                        }
-                    }
-                    return Some(Ok(new_hunk));
-                }
-                Err(crate::patch::MalformedHunkHeader(m, l)) => {
-                    if allow_dirty {
-                        // If the line isn't a hunk header, then we've reached the end of this
-                        // patch and there's "junk" at the end. Ignore the rest of the patch.
-                        return None;
-                    } else {
-                        return Some(Err(Error::MalformedHunkHeader(m, l)));
-                    }
-                }
-            }
-        }
-        None
+    #[test]
+    fn test_valid() {
+        let data = include_bytes!("../test_patches_data/binary-after-normal.patch");
+        assert_eq!(super::validate_patches(data), vec![]);
     }
-    )
 }
 
 #[cfg(test)]
-mod iter_hunks_tests {
-    use super::{Hunk, HunkLine};
+mod parse_patches_spanned_tests {
+    const TWO_FILES: &[u8] = b"diff --git a/foo b/foo
+--- a/foo
++++ b/foo
+@@ -1,2 +1,2 @@
+--- not a header
++--- still not a header
+ bar
+diff --git a/bar b/bar
+--- a/bar
++++ b/bar
+@@ -1 +1 @@
+-a
++b
+";
+
     #[test]
-    fn test_iter_hunks() {
-        let mut lines = super::splitlines(br#"@@ -391,6 +391,8 @@
-                 else:
-                     assert isinstance(hunk_line, RemoveLine)
-                 line_no += 1
-+    for line in orig_lines:
-+        yield line
-                     
- import unittest
- import os.path
+    fn test_spans() {
+        let second = TWO_FILES.windows(16).position(|w| w == b"diff --git a/bar").unwrap();
+        let spans = super::file_patch_spans(TWO_FILES);
+        assert_eq!(spans, vec![0..second, second..TWO_FILES.len()]);
+        assert!(TWO_FILES[spans[1].clone()].starts_with(b"diff --git a/bar b/bar\n"));
+    }
 
-"#);
+    #[test]
+    fn test_spans_git_without_header() {
+        // A pure rename has no --- line but must still get its own section
+        let data = b"diff --git a/old b/new
+similarity index 100%
+rename from old
+rename to new
+diff --git a/bar b/bar
+--- a/bar
++++ b/bar
+@@ -1 +1 @@
+-a
++b
+";
+        let second = data.windows(16).position(|w| w == b"diff --git a/bar").unwrap();
+        assert_eq!(
+            super::file_patch_spans(data),
+            vec![0..second, second..data.len()]
+        );
+    }
 
-        let hunks = super::iter_hunks(&mut lines, false).collect::<Result<Vec<Hunk>, crate::parse::Error>>().unwrap();
+    #[test]
+    fn test_parse_patches_spanned() {
+        let patches = super::parse_patches_spanned(TWO_FILES);
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].0.start, 0);
+        assert_eq!(patches[0].0.end, patches[1].0.start);
+        assert_eq!(patches[1].0.end, TWO_FILES.len());
 
-        let mut expected_hunk = Hunk::new(391, 6, 391, 8, None);
-        expected_hunk.lines.extend([
-            HunkLine::ContextLine(b"                else:\n".to_vec()),
-            HunkLine::ContextLine(b"                    assert isinstance(hunk_line, RemoveLine)\n".to_vec()),
-            HunkLine::ContextLine(b"                line_no += 1\n".to_vec()),
-            HunkLine::InsertLine(b"    for line in orig_lines:\n".to_vec()),
-            HunkLine::InsertLine(b"        yield line\n".to_vec()),
-            HunkLine::ContextLine(b"                    \n".to_vec()),
-            HunkLine::ContextLine(b"import unittest\n".to_vec()),
-            HunkLine::ContextLine(b"import os.path\n".to_vec())
-        ]);
+        let foo = patches[0].1.as_ref().unwrap();
+        assert_eq!(foo.orig_name, b"a/foo");
+        assert_eq!(foo.hunks[0].lines.len(), 3);
+        let bar = patches[1].1.as_ref().unwrap();
+        assert_eq!(bar.mod_name, b"b/bar");
+    }
+
+    #[test]
+    fn test_split_patch_file() {
+        let blobs = super::split_patch_file(TWO_FILES);
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(blobs.concat(), TWO_FILES);
+        for (blob, name) in blobs.iter().zip([&b"a/foo"[..], &b"a/bar"[..]]) {
+            let patches = super::parse_patches_spanned(blob);
+            assert_eq!(patches.len(), 1);
+            assert_eq!(patches[0].1.as_ref().unwrap().orig_name, name);
+        }
+    }
 
-        assert_eq!(&expected_hunk, hunks.iter().next().unwrap());
+    #[test]
+    fn test_plain_concatenated() {
+        let data = b"--- a\n+++ a\n@@ -1 +1 @@\n-x\n+y\n--- b\n+++ b\n@@ -1 +1 @@\n-x\n+y\n";
+        let spans = super::file_patch_spans(data);
+        assert_eq!(spans, vec![0..30, 30..60]);
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(super::file_patch_spans(b"").is_empty());
     }
 }
 
-pub fn parse_patch<'a, I>(iter_lines: I, allow_dirty: bool) -> Result<Box<dyn Patch>, Error>
-where
-    I: Iterator<Item = &'a [u8]> + 'a,
-{
-    let mut iter_lines = iter_lines_handle_nl(iter_lines);
+/// A change of file mode, as listed in the summary git appends to a patch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeChange {
+    /// Path of the file
+    pub path: Vec<u8>,
 
-    let ((orig_name, orig_ts), (mod_name, mod_ts)) = match get_patch_names(&mut iter_lines) {
-        Ok(names) => names,
-        Err(Error::BinaryFiles(orig_name, mod_name)) => {
-            return Ok(Box::new(BinaryPatch(orig_name, mod_name)));
-        }
-        Err(e) => return Err(e),
-    };
+    /// Old mode
+    pub old: u32,
 
-    let mut patch = UnifiedPatch::new(orig_name, orig_ts, mod_name, mod_ts);
-    for hunk in iter_hunks(&mut iter_lines, allow_dirty) {
-        patch.hunks.push(hunk?);
-    }
-    Ok(Box::new(patch))
+    /// New mode
+    pub new: u32,
+}
+
+static MODE_CHANGE_RE: once_cell::sync::Lazy<regex::bytes::Regex> =
+    lazy_regex::bytes_lazy_regex!(r"^ mode change ([0-7]+) => ([0-7]+) (.+?)\r?\n?$");
+
+/// Find the mode changes listed in the summary of a git patch
+///
+/// These are the ` mode change 100644 => 100755 path` lines that `git format-patch`
+/// and `git diff --summary` emit after the diffstat. Lines within hunks are not
+/// considered, even if they look like a summary line.
+pub fn parse_mode_changes(data: &[u8]) -> Vec<ModeChange> {
+    lines_outside_hunks(data)
+        .filter_map(|(_, line)| {
+            let captures = MODE_CHANGE_RE.captures(line)?;
+            let mode = |i| {
+                u32::from_str_radix(std::str::from_utf8(&captures[i]).ok()?, 8).ok()
+            };
+            Some(ModeChange {
+                path: captures[3].to_vec(),
+                old: mode(1)?,
+                new: mode(2)?,
+            })
+        })
+        .collect()
 }
 
+#[cfg(test)]
+mod parse_mode_changes_tests {
+    #[test]
+    fn test_summary() {
+        let data = b"--- a/foo
++++ b/foo
+@@ -1 +1 @@
+-a
++b
+---
+ foo            | 2 +-
+ bin/run.sh     | 0
+ 2 files changed, 1 insertion(+), 1 deletion(-)
+ mode change 100644 => 100755 bin/run.sh
+";
+        assert_eq!(
+            super::parse_mode_changes(data),
+            vec![super::ModeChange {
+                path: b"bin/run.sh".to_vec(),
+                old: 0o100644,
+                new: 0o100755,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_ignores_hunk_lines() {
+        let data = b"--- a/NEWS
++++ b/NEWS
+@@ -1,2 +1,2 @@
+- mode change 100644 => 100755 old
++ mode change 100644 => 100755 new
+  mode change 100644 => 100755 context
+";
+        assert_eq!(super::parse_mode_changes(data), vec![]);
+    }
+}
 
 #[cfg(test)]
 mod patches_tests {
@@ -336,11 +1968,16 @@ mod patches_tests {
     test_patch!(test_patch_7, "orig-7", "mod-7", "diff-7");
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PatchConflict {
-    line_no: usize,
-    orig_line: Vec<u8>,
-    patch_line: Vec<u8>,
+    /// Line number in the original file at which the conflict occurred
+    pub line_no: usize,
+
+    /// The line found in the original file (empty if the file ended early)
+    pub orig_line: Vec<u8>,
+
+    /// The line the patch expected
+    pub patch_line: Vec<u8>,
 }
 
 impl std::fmt::Display for PatchConflict {
@@ -357,22 +1994,65 @@ impl std::fmt::Display for PatchConflict {
 
 impl std::error::Error for PatchConflict {}
 
-struct PatchedIter<H: Iterator<Item = Hunk>, L: Iterator<Item = Vec<u8>>> {
+struct PatchedIter<'h, L: Iterator<Item = Vec<u8>>> {
     orig_lines: L,
-    hunk_lines: Vec<HunkLine>,
-    hunks: std::iter::Peekable<H>,
+    hunks: &'h [Hunk],
+    /// Index of the current hunk in `hunks`
+    hunk_index: usize,
+    /// Index of the next line of the current hunk to process
+    line_index: usize,
+    /// Index (0-based) of the first original line covered by the current hunk
+    hunk_start: usize,
     line_no: usize,
+    options: ApplyOptions,
+}
+
+/// Strip the line ending (`\n` or `\r\n`) from a line
+fn strip_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Check whether a line from the original file matches a line from the patch
+fn lines_match(orig_line: &[u8], patch_line: &[u8], options: &ApplyOptions) -> bool {
+    let (orig_line, patch_line) = if options.preserve_line_endings {
+        (strip_line_ending(orig_line), strip_line_ending(patch_line))
+    } else {
+        (orig_line, patch_line)
+    };
+    match options.expand_tabs {
+        Some(width) => expand_tabs(orig_line, width) == expand_tabs(patch_line, width),
+        None => orig_line == patch_line,
+    }
+}
+
+/// Replace tabs with spaces up to the next multiple of `width` columns
+fn expand_tabs(line: &[u8], width: usize) -> std::borrow::Cow<'_, [u8]> {
+    if !line.contains(&b'\t') {
+        return std::borrow::Cow::Borrowed(line);
+    }
+    let width = width.max(1);
+    let mut ret = Vec::with_capacity(line.len());
+    for &c in line {
+        if c == b'\t' {
+            let spaces = width - ret.len() % width;
+            ret.resize(ret.len() + spaces, b' ');
+        } else {
+            ret.push(c);
+        }
+    }
+    std::borrow::Cow::Owned(ret)
 }
 
-impl<H: Iterator<Item = Hunk>, L: Iterator<Item = Vec<u8>>> Iterator for PatchedIter<H, L> {
+impl<L: Iterator<Item = Vec<u8>>> Iterator for PatchedIter<'_, L> {
     type Item = Result<Vec<u8>, PatchConflict>;
 
     fn next(&mut self) -> Option<Result<Vec<u8>, PatchConflict>> {
         loop {
             // First, check if we just need to yield the next line from the original file.
-            match self.hunks.peek_mut() {
+            match self.hunks.get(self.hunk_index) {
                 // We're ahead of the next hunk. Yield the next line from the original file.
-                Some(hunk) if self.line_no < hunk.orig_pos => {
+                Some(_hunk) if self.line_no <= self.hunk_start => {
                     self.line_no += 1;
                     if let Some(line) = self.orig_lines.next() {
                         return Some(Ok(line));
@@ -392,59 +2072,64 @@ impl<H: Iterator<Item = Hunk>, L: Iterator<Item = Vec<u8>>> Iterator for Patched
                         return None;
                     }
                 }
-                Some(_hunk) => {
+                Some(hunk) => {
                     // We're in a hunk. Check if we need to yield a line from the hunk.
-                    if let Some(line) = self.hunk_lines.pop() {
+                    if let Some(line) = hunk.lines.get(self.line_index) {
+                        self.line_index += 1;
                         match line {
                             HunkLine::ContextLine(bytes) => {
-                                if let Some(orig_line) = self.orig_lines.next() {
-                                    if orig_line != bytes {
+                                let orig_line = if let Some(orig_line) = self.orig_lines.next() {
+                                    if !lines_match(&orig_line, bytes, &self.options) {
                                         return Some(Err(PatchConflict {
                                             line_no: self.line_no,
                                             orig_line,
-                                            patch_line: bytes,
+                                            patch_line: bytes.clone(),
                                         }));
                                     }
+                                    orig_line
                                 } else {
                                     return Some(Err(PatchConflict {
                                         line_no: self.line_no,
                                         orig_line: Vec::new(),
-                                        patch_line: bytes,
+                                        patch_line: bytes.clone(),
                                     }));
-                                }
+                                };
                                 self.line_no += 1;
-                                return Some(Ok(bytes));
+                                if self.options.preserve_line_endings
+                                    || self.options.expand_tabs.is_some()
+                                {
+                                    return Some(Ok(orig_line));
+                                }
+                                return Some(Ok(bytes.clone()));
                             }
                             HunkLine::InsertLine(bytes) => {
-                                return Some(Ok(bytes));
+                                return Some(Ok(bytes.clone()));
                             }
                             HunkLine::RemoveLine(bytes) => {
                                 if let Some(orig_line) = self.orig_lines.next() {
-                                    if orig_line != bytes {
+                                    if !lines_match(&orig_line, bytes, &self.options) {
                                         return Some(Err(PatchConflict {
                                             line_no: self.line_no,
                                             orig_line,
-                                            patch_line: bytes,
+                                            patch_line: bytes.clone(),
                                         }));
                                     }
                                 } else {
                                     return Some(Err(PatchConflict {
                                         line_no: self.line_no,
                                         orig_line: Vec::new(),
-                                        patch_line: bytes,
+                                        patch_line: bytes.clone(),
                                     }));
                                 }
                                 self.line_no += 1;
                             }
                         }
                     } else {
-                        self.hunks.next();
-                        if let Some(h) = self.hunks.peek_mut() {
-                            let mut hunk_lines = h.lines.drain(..).collect::<Vec<_>>();
-                            hunk_lines.reverse();
-                            self.hunk_lines = hunk_lines;
+                        self.hunk_index += 1;
+                        self.line_index = 0;
+                        if let Some(h) = self.hunks.get(self.hunk_index) {
+                            self.hunk_start = h.orig_start();
                         }
-
                     }
                 }
             }
@@ -454,6 +2139,23 @@ impl<H: Iterator<Item = Hunk>, L: Iterator<Item = Vec<u8>>> Iterator for Patched
 
 #[cfg(test)]
 mod iter_exact_patched_from_hunks_tests {
+    #[test]
+    fn test_large_patch_apply_twice() {
+        use crate::patch::Patch;
+        let orig = (0..5000).map(|i| format!("line {}\n", i)).collect::<String>();
+        let modified = (0..5000)
+            .map(|i| if i % 50 == 0 { format!("changed {}\n", i) } else { format!("line {}\n", i) })
+            .collect::<String>();
+        let patch = crate::diff::diff_unified(b"a", b"b", orig.as_bytes(), modified.as_bytes());
+        assert_eq!(patch.hunks.len(), 100);
+        let hunks = patch.hunks.clone();
+        for _ in 0..2 {
+            assert_eq!(patch.apply_exact(orig.as_bytes()).unwrap(), modified.as_bytes());
+        }
+        // Applying borrows the hunks rather than consuming them
+        assert_eq!(patch.hunks, hunks);
+    }
+
     #[test]
     fn test_just_context() {
         let orig_lines = vec![
@@ -465,7 +2167,7 @@ mod iter_exact_patched_from_hunks_tests {
         let mut hunk = crate::patch::Hunk::new(1, 1, 1, 1, None);
         hunk.lines.push(crate::patch::HunkLine::ContextLine(b"line 1\n".to_vec()));
         let hunks = vec![hunk];
-        let result = super::iter_exact_patched_from_hunks(orig_lines.into_iter(), hunks.into_iter()).collect::<Result<Vec<_>, _>>().unwrap();
+        let result = super::iter_exact_patched_from_hunks(orig_lines.into_iter(), &hunks).collect::<Result<Vec<_>, _>>().unwrap();
         assert_eq!(&result, &[
             b"line 1\n".to_vec(),
             b"line 2\n".to_vec(),
@@ -474,6 +2176,78 @@ mod iter_exact_patched_from_hunks_tests {
         ]);
     }
 
+    #[test]
+    fn test_trailing_blank_lines() {
+        let orig = b"a\nb\nc\n\n\n";
+        let apply = |patch: &[u8]| {
+            let patch = crate::patch::UnifiedPatch::parse_patch(super::splitlines(patch), false).unwrap();
+            crate::patch::Patch::apply_exact(&patch, orig).unwrap()
+        };
+        // Deletion hunk near EOF, not covering the trailing blank lines
+        assert_eq!(apply(b"--- x\n+++ x\n@@ -2,2 +2 @@\n b\n-c\n"), b"a\nb\n\n\n");
+        // Deletion of the last (blank) line
+        assert_eq!(apply(b"--- x\n+++ x\n@@ -5 +4,0 @@\n-\n"), b"a\nb\nc\n\n");
+        // Pure insertions go after the line given in the header
+        assert_eq!(apply(b"--- x\n+++ x\n@@ -3,0 +4 @@\n+d\n"), b"a\nb\nc\nd\n\n\n");
+        assert_eq!(apply(b"--- x\n+++ x\n@@ -5,0 +6 @@\n+d\n"), b"a\nb\nc\n\n\nd\n");
+    }
+
+    #[test]
+    fn test_preserve_line_endings() {
+        let orig_lines = vec![
+            b"line 1\r\n".to_vec(),
+            b"line 2\n".to_vec(),
+            b"line 3\r\n".to_vec(),
+            b"line 4\n".to_vec(),
+        ];
+        let mut hunk = crate::patch::Hunk::new(1, 3, 1, 3, None);
+        hunk.lines.push(crate::patch::HunkLine::ContextLine(b"line 1\n".to_vec()));
+        hunk.lines.push(crate::patch::HunkLine::RemoveLine(b"line 2\n".to_vec()));
+        hunk.lines.push(crate::patch::HunkLine::InsertLine(b"line two\n".to_vec()));
+        hunk.lines.push(crate::patch::HunkLine::ContextLine(b"line 3\n".to_vec()));
+
+        // Without the option, the differing line endings conflict
+        let exact = super::iter_exact_patched_from_hunks(orig_lines.clone().into_iter(), std::slice::from_ref(&hunk)).collect::<Result<Vec<_>, _>>();
+        assert!(exact.is_err());
+
+        let options = crate::patch::ApplyOptions { preserve_line_endings: true, ..Default::default() };
+        let result = super::iter_patched_from_hunks_with_options(orig_lines.into_iter(), &[hunk], &options).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(&result, &[
+            b"line 1\r\n".to_vec(),
+            b"line two\n".to_vec(),
+            b"line 3\r\n".to_vec(),
+            b"line 4\n".to_vec(),
+        ]);
+    }
+
+    #[test]
+    fn test_expand_tabs() {
+        let orig_lines = vec![
+            b"fn main() {\n".to_vec(),
+            b"\tlet x = 1;\n".to_vec(),
+            b"\tlet y = 2;\n".to_vec(),
+            b"}\n".to_vec(),
+        ];
+        let mut hunk = crate::patch::Hunk::new(1, 4, 1, 4, None);
+        hunk.lines.push(crate::patch::HunkLine::ContextLine(b"fn main() {\n".to_vec()));
+        hunk.lines.push(crate::patch::HunkLine::ContextLine(b"        let x = 1;\n".to_vec()));
+        hunk.lines.push(crate::patch::HunkLine::RemoveLine(b"        let y = 2;\n".to_vec()));
+        hunk.lines.push(crate::patch::HunkLine::InsertLine(b"        let y = 3;\n".to_vec()));
+        hunk.lines.push(crate::patch::HunkLine::ContextLine(b"}\n".to_vec()));
+
+        let result = super::iter_exact_patched_from_hunks(orig_lines.clone().into_iter(), std::slice::from_ref(&hunk)).collect::<Result<Vec<_>, _>>();
+        assert!(result.is_err());
+
+        let options = crate::patch::ApplyOptions { expand_tabs: Some(8), ..Default::default() };
+        let result = super::iter_patched_from_hunks_with_options(orig_lines.into_iter(), &[hunk], &options).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(&result, &[
+            b"fn main() {\n".to_vec(),
+            b"\tlet x = 1;\n".to_vec(),
+            b"        let y = 3;\n".to_vec(),
+            b"}\n".to_vec(),
+        ]);
+    }
+
     #[test]
     fn test_insert() {
         let orig_lines = vec![
@@ -486,7 +2260,7 @@ mod iter_exact_patched_from_hunks_tests {
         hunk.lines.push(crate::patch::HunkLine::InsertLine(b"line 0\n".to_vec()));
         hunk.lines.push(crate::patch::HunkLine::ContextLine(b"line 1\n".to_vec()));
         let hunks = vec![hunk];
-        let result = super::iter_exact_patched_from_hunks(orig_lines.into_iter(), hunks.into_iter()).collect::<Result<Vec<_>, _>>().unwrap();
+        let result = super::iter_exact_patched_from_hunks(orig_lines.into_iter(), &hunks).collect::<Result<Vec<_>, _>>().unwrap();
         assert_eq!(&result, &[
             b"line 0\n".to_vec(),
             b"line 1\n".to_vec(),
@@ -495,6 +2269,23 @@ mod iter_exact_patched_from_hunks_tests {
             b"line 4\n".to_vec(),
         ]);
     }
+
+    #[test]
+    fn test_insert_after_orig_pos() {
+        // As in GNU patch, "@@ -N,0 ..." inserts its lines after line N
+        let orig_lines = vec![b"line 1\n".to_vec(), b"line 2\n".to_vec()];
+        let apply = |orig_pos| {
+            let mut hunk = crate::patch::Hunk::new(orig_pos, 0, orig_pos + 1, 1, None);
+            hunk.lines.push(crate::patch::HunkLine::InsertLine(b"new\n".to_vec()));
+            super::iter_exact_patched_from_hunks(orig_lines.clone().into_iter(), &[hunk])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+                .concat()
+        };
+        assert_eq!(apply(0), b"new\nline 1\nline 2\n");
+        assert_eq!(apply(1), b"line 1\nnew\nline 2\n");
+        assert_eq!(apply(2), b"line 1\nline 2\nnew\n");
+    }
 }
 
 /// Iterate through a series of lines with a patch applied.
@@ -506,20 +2297,30 @@ mod iter_exact_patched_from_hunks_tests {
 ///   hunks: The hunks to apply to the file.
 pub fn iter_exact_patched_from_hunks<'a>(
     orig_lines: impl Iterator<Item = Vec<u8>> + 'a,
-    hunks: impl Iterator<Item = Hunk>,
-) -> impl Iterator<Item = Result<Vec<u8>, PatchConflict>> {
-    let mut hunks = hunks.peekable();
-    let mut hunk_lines = if let Some(h) = hunks.peek_mut() {
-        h.lines.drain(..).collect()
-    } else {
-        Vec::new()
-    };
-    hunk_lines.reverse();
+    hunks: &'a [Hunk],
+) -> impl Iterator<Item = Result<Vec<u8>, PatchConflict>> + 'a {
+    iter_patched_from_hunks_with_options(orig_lines, hunks, &ApplyOptions::default())
+}
+
+/// Iterate through a series of lines with a patch applied, using the given options.
+///
+/// Args:
+///   orig_lines: The original lines of the file.
+///   hunks: The hunks to apply to the file.
+///   options: Options controlling how lines are matched and emitted.
+pub fn iter_patched_from_hunks_with_options<'a>(
+    orig_lines: impl Iterator<Item = Vec<u8>> + 'a,
+    hunks: &'a [Hunk],
+    options: &ApplyOptions,
+) -> impl Iterator<Item = Result<Vec<u8>, PatchConflict>> + 'a {
     PatchedIter {
         orig_lines,
         hunks,
+        hunk_index: 0,
+        line_index: 0,
+        hunk_start: hunks.first().map_or(0, |h| h.orig_start()),
         line_no: 1,
-        hunk_lines,
+        options: options.clone(),
     }
 }
 
@@ -5,99 +5,2292 @@ use regex::bytes::Regex;
 pub enum ApplyError {
     Conflict(String),
 
+    /// A hunk did not match the original
+    HunkConflict {
+        /// Index of the hunk in the patch
+        hunk_index: usize,
+
+        /// Line number in the original file at which the conflict occurred
+        line_no: usize,
+
+        /// The line found in the original file (empty if the file ended early)
+        orig_line: Vec<u8>,
+
+        /// The line the patch expected
+        patch_line: Vec<u8>,
+    },
+
     Unapplyable,
+
+    /// A patch in a series failed to apply
+    SeriesConflict {
+        /// Index of the failing patch in the series
+        patch_index: usize,
+
+        /// Why the patch failed
+        error: Box<ApplyError>,
+    },
+}
+
+impl ApplyError {
+    fn from_conflict(hunk_index: usize, conflict: crate::parse::PatchConflict) -> Self {
+        Self::HunkConflict {
+            hunk_index,
+            line_no: conflict.line_no,
+            orig_line: conflict.orig_line,
+            patch_line: conflict.patch_line,
+        }
+    }
+}
+
+impl std::fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Conflict(reason) => write!(f, "Conflict: {}", reason),
+            Self::HunkConflict { line_no, orig_line, patch_line, .. } => {
+                // Rendered the same as a stringified PatchConflict
+                let conflict = crate::parse::PatchConflict {
+                    line_no: *line_no,
+                    orig_line: orig_line.clone(),
+                    patch_line: patch_line.clone(),
+                };
+                write!(f, "Conflict: {}", conflict)
+            }
+            Self::Unapplyable => write!(f, "Patch unapplyable"),
+            Self::SeriesConflict { patch_index, error } => {
+                write!(f, "Patch {} in series: {}", patch_index, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApplyError {}
+
+/// Result of applying a patch on a best-effort basis
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplyResult {
+    /// The patched contents, with the hunks that could be applied
+    pub output: Vec<u8>,
+
+    /// The hunks that could not be applied
+    pub rejected: Vec<Hunk>,
+
+    /// Whether the patch could not be applied at all, e.g. because it is binary
+    pub unapplyable: bool,
+}
+
+impl ApplyResult {
+    /// Whether the whole patch was applied
+    pub fn is_clean(&self) -> bool {
+        self.rejected.is_empty() && !self.unapplyable
+    }
+}
+
+/// Options controlling how a patch is applied
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    /// Ignore line endings when matching context and removed lines, and copy unchanged
+    /// lines from the original file rather than the patch, so that their original
+    /// line endings are kept.
+    pub preserve_line_endings: bool,
+
+    /// Sort hunks by their position in the original file before applying them, so
+    /// that patches listing hunks out of order can still be applied.
+    pub sort_hunks: bool,
+
+    /// Expand tabs to this many columns on both the original and patch lines before
+    /// comparing them, so that indentation with tabs matches indentation with spaces.
+    /// Unchanged lines are copied from the original file.
+    pub expand_tabs: Option<usize>,
+}
+
+/// A patch of some sort
+pub trait Patch {
+    /// Old file name
+    fn oldname(&self) -> &[u8];
+
+    /// New file name
+    fn newname(&self) -> &[u8];
+
+    fn apply_exact(&self, orig: &[u8]) -> Result<Vec<u8>, ApplyError>;
+
+    /// Apply as much of this patch as possible, returning conflicts as data
+    ///
+    /// Unlike `apply_exact`, a hunk that does not match is skipped rather than
+    /// aborting the whole patch, so that it can be written to a reject file.
+    fn apply(&self, orig: &[u8]) -> ApplyResult;
+
+    /// File name with git's synthetic `a/` (old) or `b/` (new) prefix removed
+    ///
+    /// `/dev/null` is returned unchanged.
+    fn clean_name(&self, side: Side) -> Vec<u8> {
+        self.clean_name_with(side, &PathPrefixMode::GitAB)
+    }
+
+    /// File name with the synthetic prefix described by `mode` removed
+    ///
+    /// `/dev/null` is returned unchanged.
+    fn clean_name_with(&self, side: Side, mode: &PathPrefixMode) -> Vec<u8> {
+        let name = match side {
+            Side::Old => self.oldname(),
+            Side::New => self.newname(),
+        };
+        if name == DEV_NULL {
+            return name.to_vec();
+        }
+        match mode.prefix(side) {
+            Some(prefix) => name.strip_prefix(prefix).unwrap_or(name).to_vec(),
+            None => name.to_vec(),
+        }
+    }
+}
+
+/// Synthetic prefixes used on the file names in a patch
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PathPrefixMode {
+    /// git's default `a/` (old) and `b/` (new) prefixes
+    #[default]
+    GitAB,
+
+    /// No prefixes, as produced by `git diff --no-prefix` or `diff.noprefix`
+    None,
+
+    /// Custom old and new prefixes, as produced by `git diff --src-prefix --dst-prefix`
+    Custom(Vec<u8>, Vec<u8>),
+}
+
+impl PathPrefixMode {
+    /// The prefix used for the given side, if any
+    pub fn prefix(&self, side: Side) -> Option<&[u8]> {
+        match (self, side) {
+            (Self::GitAB, Side::Old) => Some(b"a/"),
+            (Self::GitAB, Side::New) => Some(b"b/"),
+            (Self::None, _) => None,
+            (Self::Custom(old, _), Side::Old) => Some(old),
+            (Self::Custom(_, new), Side::New) => Some(new),
+        }
+    }
+}
+
+/// Name used in patch headers for a file that does not exist
+pub const DEV_NULL: &[u8] = b"/dev/null";
+
+/// Side of a patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// The original file
+    Old,
+
+    /// The modified file
+    New,
+}
+
+#[cfg(test)]
+mod clean_name_tests {
+    use super::{PathPrefixMode, Patch, Side, UnifiedPatch};
+
+    #[test]
+    fn test_clean_name() {
+        let patch = UnifiedPatch::new(b"a/src/foo.rs".to_vec(), None, b"b/src/foo.rs".to_vec(), None);
+        assert_eq!(patch.clean_name(Side::Old), b"src/foo.rs");
+        assert_eq!(patch.clean_name(Side::New), b"src/foo.rs");
+    }
+
+    #[test]
+    fn test_clean_name_dev_null() {
+        let patch = UnifiedPatch::new(b"/dev/null".to_vec(), None, b"b/new.rs".to_vec(), None);
+        assert_eq!(patch.clean_name(Side::Old), b"/dev/null");
+        assert_eq!(patch.clean_name(Side::New), b"new.rs");
+    }
+
+    #[test]
+    fn test_clean_name_wrong_side() {
+        // Only the prefix matching the side is removed
+        let patch = UnifiedPatch::new(b"b/foo".to_vec(), None, b"a/foo".to_vec(), None);
+        assert_eq!(patch.clean_name(Side::Old), b"b/foo");
+        assert_eq!(patch.clean_name(Side::New), b"a/foo");
+    }
+
+    #[test]
+    fn test_clean_name_no_prefix() {
+        let patch = UnifiedPatch::parse_patch(
+            crate::parse::splitlines(b"--- a/foo.rs\n+++ b/foo.rs\n@@ -1 +1 @@\n-x\n+y\n"),
+            false,
+        )
+        .unwrap();
+        // With --no-prefix, "a/" and "b/" are real directories
+        assert_eq!(patch.clean_name_with(Side::Old, &PathPrefixMode::None), b"a/foo.rs");
+        assert_eq!(patch.clean_name_with(Side::New, &PathPrefixMode::None), b"b/foo.rs");
+    }
+
+    #[test]
+    fn test_clean_name_custom_prefix() {
+        let patch = UnifiedPatch::new(b"old/foo".to_vec(), None, b"new/foo".to_vec(), None);
+        let mode = PathPrefixMode::Custom(b"old/".to_vec(), b"new/".to_vec());
+        assert_eq!(patch.clean_name_with(Side::Old, &mode), b"foo");
+        assert_eq!(patch.clean_name_with(Side::New, &mode), b"foo");
+        assert_eq!(patch.clean_name_with(Side::Old, &PathPrefixMode::GitAB), b"old/foo");
+    }
+}
+
+/// A binary patch
+pub struct BinaryPatch(pub Vec<u8>, pub Vec<u8>);
+
+impl Patch for BinaryPatch {
+    fn oldname(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn newname(&self) -> &[u8] {
+        &self.1
+    }
+
+    fn apply_exact(&self, _orig: &[u8]) -> Result<Vec<u8>, ApplyError> {
+        Err(ApplyError::Unapplyable)
+    }
+
+    fn apply(&self, orig: &[u8]) -> ApplyResult {
+        ApplyResult { output: orig.to_vec(), rejected: Vec::new(), unapplyable: true }
+    }
+}
+
+/// SHA-1 of `content` as git hashes a blob, as found on the `index` line of a git diff
+pub fn git_blob_sha(content: &[u8]) -> Vec<u8> {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(format!("blob {}\0", content.len()).as_bytes());
+    hasher.update(content);
+    hasher.digest().bytes().to_vec()
+}
+
+/// A binary patch that replaces the whole contents of a file
+///
+/// Only a digest of the original is stored, which is enough to detect that the
+/// patch is being applied to a different file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FullBinaryPatch {
+    /// Name of the original file
+    pub orig_name: Vec<u8>,
+
+    /// Name of the modified file
+    pub mod_name: Vec<u8>,
+
+    /// `git_blob_sha` of the expected original contents
+    pub old_sha: Vec<u8>,
+
+    /// Contents of the modified file
+    pub new_content: Vec<u8>,
+}
+
+impl FullBinaryPatch {
+    /// Create a patch that replaces `old_content` with `new_content`
+    pub fn new(orig_name: Vec<u8>, mod_name: Vec<u8>, old_content: &[u8], new_content: Vec<u8>) -> Self {
+        Self {
+            orig_name,
+            mod_name,
+            old_sha: git_blob_sha(old_content),
+            new_content,
+        }
+    }
+}
+
+impl Patch for FullBinaryPatch {
+    fn oldname(&self) -> &[u8] {
+        &self.orig_name
+    }
+
+    fn newname(&self) -> &[u8] {
+        &self.mod_name
+    }
+
+    fn apply_exact(&self, orig: &[u8]) -> Result<Vec<u8>, ApplyError> {
+        if git_blob_sha(orig) != self.old_sha {
+            return Err(ApplyError::Conflict(
+                "original contents do not match binary patch".to_string(),
+            ));
+        }
+        Ok(self.new_content.clone())
+    }
+
+    fn apply(&self, orig: &[u8]) -> ApplyResult {
+        match self.apply_exact(orig) {
+            Ok(output) => ApplyResult { output, rejected: Vec::new(), unapplyable: false },
+            Err(_) => ApplyResult { output: orig.to_vec(), rejected: Vec::new(), unapplyable: true },
+        }
+    }
+}
+
+#[cfg(test)]
+mod full_binary_patch_tests {
+    use super::{git_blob_sha, ApplyError, FullBinaryPatch, Patch};
+
+    #[test]
+    fn test_apply() {
+        let patch = FullBinaryPatch::new(
+            b"a/logo.png".to_vec(),
+            b"b/logo.png".to_vec(),
+            b"\x89PNG\0\x01",
+            b"\x89PNG\0\x02\xff".to_vec(),
+        );
+        assert_eq!(patch.apply_exact(b"\x89PNG\0\x01").unwrap(), b"\x89PNG\0\x02\xff");
+        assert!(matches!(
+            patch.apply_exact(b"\x89PNG\0\x03"),
+            Err(ApplyError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_git_blob_sha() {
+        // As printed by `git hash-object`
+        let hex = |sha: Vec<u8>| sha.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(git_blob_sha(b"")), "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391");
+        assert_eq!(hex(git_blob_sha(b"hello\n")), "ce013625030ba8dba906f756967f9e9ca394464a");
+    }
+}
+
+/// A block of lines that a patch moves to a different place in the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovedBlock {
+    /// Line number (1-based) of the first line of the block in the original file
+    pub from: usize,
+
+    /// Line number (1-based) of the first line of the block in the modified file
+    pub to: usize,
+
+    /// The lines that were moved
+    pub lines: Vec<Vec<u8>>,
+}
+
+/// Number of lines inserted and removed by a patch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PatchStats {
+    /// Number of inserted lines
+    pub insertions: usize,
+
+    /// Number of removed lines
+    pub deletions: usize,
+
+    /// Number of hunks
+    pub hunks: usize,
+}
+
+impl std::ops::AddAssign for PatchStats {
+    fn add_assign(&mut self, other: Self) {
+        self.insertions += other.insertions;
+        self.deletions += other.deletions;
+        self.hunks += other.hunks;
+    }
+}
+
+/// Width, in columns, that `diffstat` fits its lines into
+const DIFFSTAT_WIDTH: usize = 80;
+
+/// Summarize a set of patches in the style of `diffstat` or `git diff --stat`
+///
+/// Each file gets a line like ` foo.c | 12 +++++++-----`, followed by a total
+/// line. Bars are scaled down so that no line is wider than 80 columns.
+pub fn diffstat(patches: &[UnifiedPatch]) -> String {
+    let files = patches
+        .iter()
+        .map(|patch| {
+            let side = if patch.mod_name == DEV_NULL { Side::Old } else { Side::New };
+            (String::from_utf8_lossy(&patch.clean_name(side)).into_owned(), patch.stats())
+        })
+        .collect::<Vec<_>>();
+    let name_width = files.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0);
+    let max_changes = files.iter().map(|(_, s)| s.insertions + s.deletions).max().unwrap_or(0);
+    let count_width = max_changes.to_string().len();
+    // " name | count bar"
+    let bar_width = DIFFSTAT_WIDTH.saturating_sub(name_width + count_width + 5);
+
+    let mut ret = String::new();
+    let mut total = PatchStats::default();
+    for (name, stats) in &files {
+        let changes = stats.insertions + stats.deletions;
+        let scaled = if max_changes <= bar_width || changes == 0 {
+            changes
+        } else {
+            (changes * bar_width / max_changes).clamp(1, bar_width.max(1))
+        };
+        let plus = (stats.insertions * scaled + changes / 2)
+            .checked_div(changes)
+            .unwrap_or(0);
+        ret.push_str(&format!(
+            " {:<name_width$} | {:>count_width$} {}{}\n",
+            name,
+            changes,
+            "+".repeat(plus),
+            "-".repeat(scaled - plus),
+        ));
+        total += *stats;
+    }
+    let plural = |n: usize, one: &str, many: &str| format!("{} {}", n, if n == 1 { one } else { many });
+    ret.push_str(&format!(
+        " {}, {}, {}\n",
+        plural(files.len(), "file changed", "files changed"),
+        plural(total.insertions, "insertion(+)", "insertions(+)"),
+        plural(total.deletions, "deletion(-)", "deletions(-)"),
+    ));
+    ret
+}
+
+#[cfg(test)]
+mod diffstat_tests {
+    use super::{diffstat, Hunk, HunkLine, UnifiedPatch};
+
+    fn patch(name: &str, insertions: usize, deletions: usize) -> UnifiedPatch {
+        let mut patch = UnifiedPatch::new(
+            format!("a/{}", name).into_bytes(),
+            None,
+            format!("b/{}", name).into_bytes(),
+            None,
+        );
+        let mut hunk = Hunk::new(1, deletions, 1, insertions, None);
+        hunk.lines.extend((0..deletions).map(|_| HunkLine::RemoveLine(b"old\n".to_vec())));
+        hunk.lines.extend((0..insertions).map(|_| HunkLine::InsertLine(b"new\n".to_vec())));
+        patch.hunks.push(hunk);
+        patch
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = patch("foo", 3, 2).stats();
+        assert_eq!(stats, super::PatchStats { insertions: 3, deletions: 2, hunks: 1 });
+    }
+
+    #[test]
+    fn test_diffstat() {
+        let patches = vec![patch("foo.c", 7, 5), patch("src/bar.rs", 1, 0)];
+        assert_eq!(
+            diffstat(&patches),
+            concat!(
+                " foo.c      | 12 +++++++-----\n",
+                " src/bar.rs |  1 +\n",
+                " 2 files changed, 8 insertions(+), 5 deletions(-)\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_diffstat_scaled() {
+        let patches = vec![patch("big", 3000, 1000), patch("small", 1, 0)];
+        let stat = diffstat(&patches);
+        for line in stat.lines() {
+            assert!(line.len() <= 80, "{:?} is too long", line);
+        }
+        let lines = stat.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], format!(" big   | 4000 {}{}", "+".repeat(50), "-".repeat(16)));
+        assert_eq!(lines[1], " small |    1 +");
+        assert_eq!(lines[2], " 2 files changed, 3001 insertions(+), 1000 deletions(-)");
+    }
+}
+
+/// A unified diff style patch
+///
+/// Use `UnifiedPatch::new` or `UnifiedPatchBuilder` to create one; further fields
+/// may be added in future versions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct UnifiedPatch {
+    /// Name of the original file
+    pub orig_name: Vec<u8>,
+
+    /// Timestamp for the original file
+    pub orig_ts: Option<Vec<u8>>,
+
+    /// Name of the modified file
+    pub mod_name: Vec<u8>,
+
+    /// Timestamp for the modified file
+    pub mod_ts: Option<Vec<u8>>,
+
+    /// List of hunks
+    pub hunks: Vec<Hunk>,
+
+    /// The `---` and `+++` lines exactly as they were parsed
+    ///
+    /// While they still match the names and timestamps, `write` emits these bytes
+    /// instead of rebuilding the header, so that parsing and writing a patch is
+    /// byte-exact.
+    pub(crate) raw_header: Option<Vec<u8>>,
+
+    /// Extended header information, for patches produced by `git diff`
+    pub git: Option<GitMetadata>,
+
+    /// Whether the patch used `\r\n` line endings before they were normalized; if
+    /// set, `write` emits `\r\n` line endings again
+    pub crlf: bool,
+
+    /// Problems found while parsing the patch that did not prevent parsing it
+    pub warnings: Vec<String>,
+}
+
+/// Information from the extended header lines of a `git diff`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GitMetadata {
+    /// Old name from the `diff --git` line, including its `a/` prefix
+    pub old_name: Vec<u8>,
+
+    /// New name from the `diff --git` line, including its `b/` prefix
+    pub new_name: Vec<u8>,
+
+    /// Mode of the old file, from `old mode`, `deleted file mode` or `index`
+    pub old_mode: Option<u32>,
+
+    /// Mode of the new file, from `new mode`, `new file mode` or `index`
+    pub new_mode: Option<u32>,
+
+    /// Abbreviated blob hash of the old file, from the `index` line
+    pub old_hash: Option<Vec<u8>>,
+
+    /// Abbreviated blob hash of the new file, from the `index` line
+    pub new_hash: Option<Vec<u8>>,
+
+    /// Source of a rename, from `rename from`
+    pub rename_from: Option<Vec<u8>>,
+
+    /// Target of a rename, from `rename to`
+    pub rename_to: Option<Vec<u8>>,
+
+    /// Source of a copy, from `copy from`
+    pub copy_from: Option<Vec<u8>>,
+
+    /// Target of a copy, from `copy to`
+    pub copy_to: Option<Vec<u8>>,
+
+    /// Similarity percentage of a rename or copy, from `similarity index`
+    pub similarity: Option<u32>,
+
+    /// Whether the file is created by the patch (`new file mode`)
+    pub new_file: bool,
+
+    /// Whether the file is deleted by the patch (`deleted file mode`)
+    pub deleted_file: bool,
+}
+
+impl GitMetadata {
+    /// The metadata for the reverse of the patch
+    pub fn reverse(&self) -> GitMetadata {
+        GitMetadata {
+            old_name: self.new_name.clone(),
+            new_name: self.old_name.clone(),
+            old_mode: self.new_mode,
+            new_mode: self.old_mode,
+            old_hash: self.new_hash.clone(),
+            new_hash: self.old_hash.clone(),
+            rename_from: self.rename_to.clone(),
+            rename_to: self.rename_from.clone(),
+            // Undoing a copy removes the target rather than copying it back
+            copy_from: None,
+            copy_to: None,
+            similarity: self.similarity,
+            new_file: self.deleted_file,
+            deleted_file: self.new_file,
+        }
+    }
+}
+
+impl UnifiedPatch {
+    pub fn new(
+        orig_name: Vec<u8>,
+        orig_ts: Option<Vec<u8>>,
+        mod_name: Vec<u8>,
+        mod_ts: Option<Vec<u8>>,
+    ) -> Self {
+        Self {
+            orig_name,
+            orig_ts,
+            mod_name,
+            mod_ts,
+            hunks: Vec::new(),
+            raw_header: None,
+            git: None,
+            crlf: false,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Sort the hunks by their position in the original file
+    pub fn sort_hunks(&mut self) {
+        self.hunks.sort_by_key(|hunk| hunk.orig_pos);
+    }
+
+    /// Whether applying this patch would leave the file unchanged
+    ///
+    /// This is the case when no hunk inserts or removes any lines.
+    pub fn is_noop(&self) -> bool {
+        self.hunks.iter().all(|hunk| {
+            hunk.lines
+                .iter()
+                .all(|line| matches!(line, HunkLine::ContextLine(_)))
+        })
+    }
+
+    /// Number of hunks in this patch
+    pub fn hunk_count(&self) -> usize {
+        self.hunks.len()
+    }
+
+    /// Count the lines inserted and removed by this patch
+    pub fn stats(&self) -> PatchStats {
+        let mut stats = PatchStats {
+            hunks: self.hunks.len(),
+            ..Default::default()
+        };
+        for line in self.hunks.iter().flat_map(|hunk| hunk.lines.iter()) {
+            match line {
+                HunkLine::InsertLine(_) => stats.insertions += 1,
+                HunkLine::RemoveLine(_) => stats.deletions += 1,
+                HunkLine::ContextLine(_) => {}
+            }
+        }
+        stats
+    }
+
+    /// The hunk with the most lines, if any
+    ///
+    /// If several hunks are equally large, the first one is returned.
+    pub fn largest_hunk(&self) -> Option<&Hunk> {
+        self.hunks
+            .iter()
+            .rev()
+            .max_by_key(|hunk| hunk.lines.len())
+    }
+
+    /// Create the patch that undoes this one, like `patch -R`
+    ///
+    /// The file names, timestamps and hunk ranges are swapped, and inserted lines
+    /// become removed lines and vice versa. Lines keep their order, so reversing
+    /// twice gives back the original patch.
+    pub fn reverse(&self) -> UnifiedPatch {
+        // Swap the raw header lines too, so that they stay byte-exact
+        let raw_header = self.raw_header.as_ref().and_then(|raw_header| {
+            match crate::parse::splitlines(raw_header).collect::<Vec<_>>()[..] {
+                [first, second] => {
+                    let swap = |line: &[u8]| {
+                        if let Some(rest) = line.strip_prefix(b"--- ") {
+                            Some([b"+++ ", rest].concat())
+                        } else {
+                            line.strip_prefix(b"+++ ").map(|rest| [b"--- ", rest].concat())
+                        }
+                    };
+                    Some([swap(second)?, swap(first)?].concat())
+                }
+                _ => None,
+            }
+        });
+        UnifiedPatch {
+            orig_name: self.mod_name.clone(),
+            orig_ts: self.mod_ts.clone(),
+            mod_name: self.orig_name.clone(),
+            mod_ts: self.orig_ts.clone(),
+            hunks: self.hunks.iter().map(|hunk| hunk.reverse()).collect(),
+            raw_header,
+            git: self.git.as_ref().map(|git| git.reverse()),
+            crlf: self.crlf,
+            warnings: self.warnings.clone(),
+        }
+    }
+
+    /// Undo this patch, by applying its reverse to the patched contents
+    pub fn apply_reverse(&self, orig: &[u8]) -> Result<Vec<u8>, ApplyError> {
+        self.reverse().apply_exact(orig)
+    }
+
+    /// Relocate this patch under a directory, by prepending `prefix` to both file names
+    ///
+    /// `/dev/null` is left alone.
+    pub fn prefix_paths(&mut self, prefix: &[u8]) {
+        for name in [&mut self.orig_name, &mut self.mod_name] {
+            if name != DEV_NULL {
+                *name = [prefix, name.as_slice()].concat();
+            }
+        }
+        self.raw_header = None;
+    }
+
+    /// Parse the timestamp of the original file, if there is one
+    pub fn orig_timestamp(
+        &self,
+    ) -> Option<Result<chrono::DateTime<chrono::FixedOffset>, crate::timestamp::ParsePatchDateError>>
+    {
+        self.orig_ts.as_deref().map(crate::timestamp::parse_patch_timestamp)
+    }
+
+    /// Parse the timestamp of the modified file, if there is one
+    pub fn mod_timestamp(
+        &self,
+    ) -> Option<Result<chrono::DateTime<chrono::FixedOffset>, crate::timestamp::ParsePatchDateError>>
+    {
+        self.mod_ts.as_deref().map(crate::timestamp::parse_patch_timestamp)
+    }
+
+    /// Set the timestamp of the original file, formatted the way GNU diff does
+    pub fn set_orig_timestamp(&mut self, dt: chrono::DateTime<chrono::FixedOffset>) {
+        self.orig_ts = Some(crate::timestamp::format_patch_timestamp(dt));
+        self.raw_header = None;
+    }
+
+    /// Set the timestamp of the modified file, formatted the way GNU diff does
+    pub fn set_mod_timestamp(&mut self, dt: chrono::DateTime<chrono::FixedOffset>) {
+        self.mod_ts = Some(crate::timestamp::format_patch_timestamp(dt));
+        self.raw_header = None;
+    }
+
+    /// Serialize the file header (the `---` and `+++` lines)
+    pub fn get_header(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        for (prefix, name, ts) in [
+            (&b"--- "[..], &self.orig_name, &self.orig_ts),
+            (&b"+++ "[..], &self.mod_name, &self.mod_ts),
+        ] {
+            header.extend_from_slice(prefix);
+            header.extend_from_slice(name);
+            if let Some(ts) = ts {
+                header.push(b'\t');
+                header.extend_from_slice(ts);
+            }
+            header.push(b'\n');
+        }
+        header
+    }
+
+    /// Whether the parsed header lines still describe the current names and timestamps
+    fn raw_header_matches(&self, raw_header: &[u8]) -> bool {
+        crate::parse::splitlines(raw_header).all(|line| {
+            let (prefix, name, ts) = match line.get(..4) {
+                Some(b"--- ") => (b"--- ", &self.orig_name, &self.orig_ts),
+                Some(b"+++ ") => (b"+++ ", &self.mod_name, &self.mod_ts),
+                _ => return false,
+            };
+            crate::parse::parse_name_line(line, prefix, "", |_| {
+                crate::parse::Error::MalformedPatchHeader("", Vec::new())
+            })
+                .is_ok_and(|(line_name, line_ts)| line_name == *name && line_ts == *ts)
+        })
+    }
+
+    /// Write this patch in unified diff format
+    pub fn write<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let header = match &self.raw_header {
+            Some(raw_header) if self.raw_header_matches(raw_header) => raw_header.clone(),
+            _ => self.get_header(),
+        };
+        for chunk in std::iter::once(header).chain(self.hunks.iter().map(|hunk| hunk.as_bytes())) {
+            if self.crlf {
+                for line in crate::parse::splitlines(&chunk) {
+                    match line.strip_suffix(b"\n") {
+                        Some(line) if !line.ends_with(b"\r") => {
+                            w.write_all(line)?;
+                            w.write_all(b"\r\n")?;
+                        }
+                        _ => w.write_all(line)?,
+                    }
+                }
+            } else {
+                w.write_all(&chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write this patch in unified diff format, after checking that every hunk's
+    /// ranges match its lines
+    ///
+    /// Fails with an `InvalidData` error, without writing anything, if a hunk is
+    /// inconsistent.
+    pub fn write_checked<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        for hunk in &self.hunks {
+            hunk.validate()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
+        self.write(w)
+    }
+
+    /// Serialize this patch in unified diff format
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Write this patch, highlighting it for display on a terminal
+    ///
+    /// With `ColorStyle::None` this produces the same output as `write`.
+    pub fn write_colored<W: std::io::Write>(
+        &self,
+        w: &mut W,
+        style: ColorStyle,
+    ) -> std::io::Result<()> {
+        if style == ColorStyle::None {
+            return self.write(w);
+        }
+        for line in crate::parse::splitlines(&self.get_header()) {
+            write_colored_line(w, line, ANSI_BOLD)?;
+        }
+        for hunk in &self.hunks {
+            write_colored_line(w, &hunk.get_header(), ANSI_CYAN)?;
+            for line in &hunk.lines {
+                let color = match line {
+                    HunkLine::ContextLine(_) => None,
+                    HunkLine::InsertLine(_) => Some(ANSI_GREEN),
+                    HunkLine::RemoveLine(_) => Some(ANSI_RED),
+                };
+                let bytes = line.as_bytes();
+                let mut lines = crate::parse::splitlines(&bytes);
+                let first = lines.next().unwrap();
+                match color {
+                    Some(color) => write_colored_line(w, first, color)?,
+                    None => w.write_all(first)?,
+                }
+                // The "no newline" marker, if any, is left uncolored
+                for rest in lines {
+                    w.write_all(rest)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How to highlight a patch when writing it out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorStyle {
+    /// No highlighting
+    None,
+
+    /// ANSI escape codes, for terminals
+    Ansi,
+}
+
+const ANSI_RESET: &[u8] = b"\x1b[0m";
+const ANSI_BOLD: &[u8] = b"\x1b[1m";
+const ANSI_RED: &[u8] = b"\x1b[31m";
+const ANSI_GREEN: &[u8] = b"\x1b[32m";
+const ANSI_CYAN: &[u8] = b"\x1b[36m";
+
+/// Write a line wrapped in a color code, keeping the newline outside the escape sequence
+fn write_colored_line<W: std::io::Write>(
+    w: &mut W,
+    line: &[u8],
+    color: &[u8],
+) -> std::io::Result<()> {
+    let (content, newline) = match line.strip_suffix(b"\n") {
+        Some(content) => (content, &b"\n"[..]),
+        None => (line, &b""[..]),
+    };
+    w.write_all(color)?;
+    w.write_all(content)?;
+    w.write_all(ANSI_RESET)?;
+    w.write_all(newline)
+}
+
+#[cfg(test)]
+mod timestamp_tests {
+    use super::UnifiedPatch;
+
+    #[test]
+    fn test_timestamps() {
+        let patch = UnifiedPatch::new(
+            b"foo".to_vec(),
+            Some(b"2009-10-14 19:49:59 +0000".to_vec()),
+            b"foo".to_vec(),
+            Some(b"(working copy)".to_vec()),
+        );
+        assert_eq!(patch.orig_timestamp().unwrap().unwrap().timestamp(), 1255549799);
+        assert!(patch.mod_timestamp().unwrap().is_err());
+        assert!(UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None).orig_timestamp().is_none());
+    }
+
+    #[test]
+    fn test_set_timestamp() {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        let dt = chrono::DateTime::parse_from_rfc3339("2024-01-01T12:34:56Z").unwrap();
+        patch.set_orig_timestamp(dt);
+        assert_eq!(patch.get_header(), b"--- a\t2024-01-01 12:34:56.000000 +0000\n+++ a\n");
+        assert_eq!(patch.orig_timestamp().unwrap().unwrap(), dt);
+    }
+}
+
+#[cfg(test)]
+mod write_tests {
+    use super::{ColorStyle, Hunk, HunkLine, UnifiedPatch};
+
+    fn sample_patch() -> UnifiedPatch {
+        let mut patch = UnifiedPatch::new(
+            b"foo".to_vec(),
+            Some(b"2009-10-14 19:49:59 +0000".to_vec()),
+            b"foo".to_vec(),
+            None,
+        );
+        let mut hunk = Hunk::new(1, 2, 1, 2, None);
+        hunk.lines.extend([
+            HunkLine::ContextLine(b"same\n".to_vec()),
+            HunkLine::RemoveLine(b"old\n".to_vec()),
+            HunkLine::InsertLine(b"new".to_vec()),
+        ]);
+        patch.hunks.push(hunk);
+        patch
+    }
+
+    #[test]
+    fn test_write() {
+        let patch = sample_patch();
+        assert_eq!(
+            patch.as_bytes(),
+            b"--- foo\t2009-10-14 19:49:59 +0000\n+++ foo\n@@ -1,2 +1,2 @@\n same\n-old\n+new\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_write_colored_none() {
+        let patch = sample_patch();
+        let mut out = Vec::new();
+        patch.write_colored(&mut out, ColorStyle::None).unwrap();
+        assert_eq!(out, patch.as_bytes());
+    }
+
+    #[test]
+    fn test_write_colored_ansi() {
+        let patch = sample_patch();
+        let mut out = Vec::new();
+        patch.write_colored(&mut out, ColorStyle::Ansi).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\x1b[36m@@ -1,2 +1,2 @@\x1b[0m\n"));
+        assert!(out.contains("\x1b[31m-old\x1b[0m\n"));
+        assert!(out.contains("\x1b[32m+new\x1b[0m\n\\ No newline at end of file\n"));
+        assert!(out.contains("\n same\n"));
+    }
+}
+
+impl Patch for UnifiedPatch {
+    fn oldname(&self) -> &[u8] {
+        &self.orig_name
+    }
+
+    fn newname(&self) -> &[u8] {
+        &self.mod_name
+    }
+
+    fn apply_exact(&self, orig: &[u8]) -> Result<Vec<u8>, ApplyError> {
+        self.apply_with_options(orig, &ApplyOptions::default())
+    }
+
+    fn apply(&self, orig: &[u8]) -> ApplyResult {
+        let orig_lines = crate::parse::splitlines(orig).collect::<Vec<_>>();
+        let mut output = Vec::new();
+        let mut rejected = Vec::new();
+        let mut cursor = 0;
+        for hunk in &self.hunks {
+            match hunk.place_exact(&orig_lines, cursor) {
+                Ok(placement) => cursor = placement.apply(hunk, &orig_lines, cursor, &mut output),
+                Err(_) => rejected.push(hunk.clone()),
+            }
+        }
+        let output = output.into_iter().map(|(_, line)| line).chain(orig_lines[cursor..].iter().copied());
+        ApplyResult { output: output.collect::<Vec<_>>().concat(), rejected, unapplyable: false }
+    }
+}
+
+impl UnifiedPatch {
+    /// Apply this patch, with options controlling how lines are matched
+    pub fn apply_with_options(
+        &self,
+        orig: &[u8],
+        options: &ApplyOptions,
+    ) -> Result<Vec<u8>, ApplyError> {
+        let orig_lines = crate::parse::splitlines(orig).map(|l| l.to_vec());
+        Ok(self.patched_lines(orig_lines, options)?.concat())
+    }
+
+    /// Apply this patch to a file that has already been split into lines
+    ///
+    /// This avoids joining and re-splitting the lines when applying several patches
+    /// in a row. Conflicts are reported the same way as by `apply_exact`.
+    ///
+    /// # Returns
+    /// The lines of the patched file
+    pub fn apply_to_lines(&self, lines: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, ApplyError> {
+        self.patched_lines(lines.iter().cloned(), &ApplyOptions::default())
+    }
+
+    fn patched_lines(
+        &self,
+        orig_lines: impl Iterator<Item = Vec<u8>>,
+        options: &ApplyOptions,
+    ) -> Result<Vec<Vec<u8>>, ApplyError> {
+        let mut order = (0..self.hunks.len()).collect::<Vec<_>>();
+        let hunks = if options.sort_hunks {
+            order.sort_by_key(|&i| self.hunks[i].orig_pos);
+            std::borrow::Cow::Owned(order.iter().map(|&i| self.hunks[i].clone()).collect())
+        } else {
+            std::borrow::Cow::Borrowed(&self.hunks)
+        };
+        crate::parse::iter_patched_from_hunks_with_options(
+            orig_lines,
+            &hunks, options).collect::<Result<Vec<Vec<u8>>, crate::parse::PatchConflict>>()
+            .map_err(|e| {
+                // The conflict is in the last hunk that starts before it
+                let applied = hunks
+                    .iter()
+                    .rposition(|hunk| hunk.orig_start() < e.line_no)
+                    .unwrap_or(0);
+                ApplyError::from_conflict(order.get(applied).copied().unwrap_or(0), e)
+            })
+    }
+}
+
+/// Strip leading path components from a file name, like `patch -pN` does
+///
+/// Consecutive slashes count as a single separator.
+///
+/// # Arguments
+/// * `path` - The path to strip
+/// * `strip` - Number of leading components to remove
+///
+/// # Returns
+/// The stripped path, or `None` if the path does not have enough components
+pub fn strip_prefix(path: &[u8], strip: usize) -> Option<&[u8]> {
+    let mut rest = path;
+    for _ in 0..strip {
+        let index = rest.iter().position(|&c| c == b'/')?;
+        rest = &rest[index + 1..];
+        while let Some(stripped) = rest.strip_prefix(b"/") {
+            rest = stripped;
+        }
+    }
+    if rest.is_empty() {
+        None
+    } else {
+        Some(rest)
+    }
+}
+
+#[cfg(test)]
+mod strip_prefix_tests {
+    use super::strip_prefix;
+
+    #[test]
+    fn test_strip_prefix() {
+        assert_eq!(strip_prefix(b"a/src/foo.rs", 0), Some(&b"a/src/foo.rs"[..]));
+        assert_eq!(strip_prefix(b"a/src/foo.rs", 1), Some(&b"src/foo.rs"[..]));
+        assert_eq!(strip_prefix(b"a//src/foo.rs", 2), Some(&b"foo.rs"[..]));
+        assert_eq!(strip_prefix(b"a/src/foo.rs", 3), None);
+    }
+}
+
+/// Remove a directory prefix from the file names of a set of patches
+///
+/// Names that do not start with `prefix` are left alone.
+pub fn strip_common_path_prefix(patches: &mut [UnifiedPatch], prefix: &[u8]) {
+    for patch in patches {
+        for name in [&mut patch.orig_name, &mut patch.mod_name] {
+            if let Some(stripped) = name.strip_prefix(prefix) {
+                *name = stripped.to_vec();
+            }
+        }
+        patch.raw_header = None;
+    }
+}
+
+#[cfg(test)]
+mod sort_hunks_tests {
+    use super::{ApplyOptions, Hunk, HunkLine, Patch, UnifiedPatch};
+
+    fn descending_patch() -> UnifiedPatch {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        for (pos, old, new) in [(5, "e", "E"), (3, "c", "C"), (1, "a", "A")] {
+            let mut hunk = Hunk::new(pos, 1, pos, 1, None);
+            hunk.lines.push(HunkLine::RemoveLine(format!("{}\n", old).into_bytes()));
+            hunk.lines.push(HunkLine::InsertLine(format!("{}\n", new).into_bytes()));
+            patch.hunks.push(hunk);
+        }
+        patch
+    }
+
+    #[test]
+    fn test_sort_hunks() {
+        let orig = b"a\nb\nc\nd\ne\n";
+        let mut patch = descending_patch();
+        assert!(patch.apply_exact(orig).is_err());
+
+        patch.sort_hunks();
+        assert_eq!(
+            patch.hunks.iter().map(|h| h.orig_pos).collect::<Vec<_>>(),
+            vec![1, 3, 5]
+        );
+        assert_eq!(patch.apply_exact(orig).unwrap(), b"A\nb\nC\nd\nE\n");
+    }
+
+    #[test]
+    fn test_apply_sorted() {
+        let orig = b"a\nb\nc\nd\ne\n";
+        let patch = descending_patch();
+        let options = ApplyOptions {
+            sort_hunks: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            patch.apply_with_options(orig, &options).unwrap(),
+            b"A\nb\nC\nd\nE\n"
+        );
+    }
+}
+
+#[cfg(test)]
+mod is_noop_tests {
+    use super::{Hunk, HunkLine, UnifiedPatch};
+
+    #[test]
+    fn test_context_only() {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        let mut hunk = Hunk::new(1, 2, 1, 2, None);
+        hunk.lines.push(HunkLine::ContextLine(b"a\n".to_vec()));
+        hunk.lines.push(HunkLine::ContextLine(b"b\n".to_vec()));
+        patch.hunks.push(hunk);
+        assert!(patch.is_noop());
+
+        let mut hunk = Hunk::new(5, 1, 5, 2, None);
+        hunk.lines.push(HunkLine::ContextLine(b"e\n".to_vec()));
+        hunk.lines.push(HunkLine::InsertLine(b"f\n".to_vec()));
+        patch.hunks.push(hunk);
+        assert!(!patch.is_noop());
+    }
+}
+
+#[cfg(test)]
+mod hunk_count_tests {
+    use super::UnifiedPatch;
+
+    #[test]
+    fn test_multi_hunk() {
+        let data = include_bytes!("../test_patches_data/diff");
+        let patch = UnifiedPatch::parse_patch(crate::parse::splitlines(data), false).unwrap();
+        assert_eq!(patch.hunk_count(), 48);
+        let largest = patch.largest_hunk().unwrap();
+        assert_eq!((largest.orig_pos, largest.orig_range), (1066, 224));
+    }
+
+    #[test]
+    fn test_empty() {
+        let patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        assert_eq!(patch.hunk_count(), 0);
+        assert!(patch.largest_hunk().is_none());
+    }
+}
+
+#[cfg(test)]
+mod prefix_paths_tests {
+    use super::UnifiedPatch;
+
+    #[test]
+    fn test_prefix_paths() {
+        let mut patch = UnifiedPatch::new(b"foo/bar.c".to_vec(), None, b"foo/bar.c".to_vec(), None);
+        patch.prefix_paths(b"vendor/");
+        assert_eq!(patch.orig_name, b"vendor/foo/bar.c");
+        assert_eq!(patch.mod_name, b"vendor/foo/bar.c");
+    }
+
+    #[test]
+    fn test_prefix_paths_dev_null() {
+        let mut patch = UnifiedPatch::new(b"/dev/null".to_vec(), None, b"new.c".to_vec(), None);
+        patch.prefix_paths(b"vendor/");
+        assert_eq!(patch.orig_name, b"/dev/null");
+        assert_eq!(patch.mod_name, b"vendor/new.c");
+    }
+}
+
+#[cfg(test)]
+mod strip_common_path_prefix_tests {
+    use super::{strip_common_path_prefix, UnifiedPatch};
+
+    #[test]
+    fn test_strip() {
+        let mut patches = vec![
+            UnifiedPatch::new(b"project/foo".to_vec(), None, b"project/foo".to_vec(), None),
+            UnifiedPatch::new(b"/dev/null".to_vec(), None, b"project/sub/bar".to_vec(), None),
+            UnifiedPatch::new(b"other/baz".to_vec(), None, b"other/baz".to_vec(), None),
+        ];
+        strip_common_path_prefix(&mut patches, b"project/");
+        assert_eq!(patches[0].orig_name, b"foo");
+        assert_eq!(patches[0].mod_name, b"foo");
+        assert_eq!(patches[1].orig_name, b"/dev/null");
+        assert_eq!(patches[1].mod_name, b"sub/bar");
+        assert_eq!(patches[2].orig_name, b"other/baz");
+    }
+}
+
+/// Apply a patch, trying strip levels until the patched file can be found
+///
+/// Each strip level from 0 up to the number of directories in the file name is tried
+/// in turn; the first level for which `resolve` finds the file and the patch applies
+/// cleanly is used.
+///
+/// # Arguments
+/// * `patch` - The patch to apply
+/// * `resolve` - Returns the contents of the file at a (stripped) path, if it exists
+///
+/// # Returns
+/// The strip level that was used and the patched contents
+pub fn apply_autodetect_strip(
+    patch: &UnifiedPatch,
+    resolve: impl Fn(&[u8]) -> Option<Vec<u8>>,
+) -> Result<(usize, Vec<u8>), ApplyError> {
+    let name = if patch.orig_name == DEV_NULL {
+        &patch.mod_name
+    } else {
+        &patch.orig_name
+    };
+    let max_strip = name.iter().filter(|&&c| c == b'/').count();
+    let mut last_error = ApplyError::Unapplyable;
+    for strip in 0..=max_strip {
+        let path = match strip_prefix(name, strip) {
+            Some(path) => path,
+            None => break,
+        };
+        if let Some(orig) = resolve(path) {
+            match patch.apply_exact(&orig) {
+                Ok(patched) => return Ok((strip, patched)),
+                Err(e) => last_error = e,
+            }
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod apply_autodetect_strip_tests {
+    use super::{apply_autodetect_strip, Hunk, HunkLine, UnifiedPatch};
+
+    #[test]
+    fn test_p1() {
+        let mut patch = UnifiedPatch::new(b"a/src/foo".to_vec(), None, b"b/src/foo".to_vec(), None);
+        let mut hunk = Hunk::new(1, 1, 1, 1, None);
+        hunk.lines.push(HunkLine::RemoveLine(b"old\n".to_vec()));
+        hunk.lines.push(HunkLine::InsertLine(b"new\n".to_vec()));
+        patch.hunks.push(hunk);
+
+        let resolve = |path: &[u8]| {
+            if path == b"src/foo" {
+                Some(b"old\n".to_vec())
+            } else {
+                None
+            }
+        };
+        assert_eq!(
+            apply_autodetect_strip(&patch, resolve).unwrap(),
+            (1, b"new\n".to_vec())
+        );
+        assert!(apply_autodetect_strip(&patch, |_| None).is_err());
+    }
+}
+
+/// Why `apply_all_collect` could not patch a file
+#[derive(Debug)]
+pub enum FileFailure {
+    /// `resolve` did not return the contents of the original file
+    Missing,
+
+    /// The conflict for each hunk that does not match the original
+    Conflicts(Vec<crate::parse::PatchConflict>),
+
+    /// Every hunk matched, but the patch still failed to apply
+    Error(ApplyError),
+}
+
+/// Files that were patched and files that could not be, as returned by `apply_all_collect`
+#[derive(Debug)]
+pub struct CollectedResults {
+    /// The patched files with their new contents
+    pub applied: Vec<(Vec<u8>, Vec<u8>)>,
+
+    /// The files that could not be patched, with the reason
+    pub failed: Vec<(Vec<u8>, FileFailure)>,
+}
+
+/// Apply a set of patches, collecting the files that applied and the conflicts for
+/// those that did not
+///
+/// Every patch is attempted, regardless of whether earlier ones failed. Each patch's
+/// file is identified by its new name (or its old name, for deletions).
+///
+/// # Arguments
+/// * `patches` - The patches to apply
+/// * `resolve` - Returns the contents of the original file for a patch's old name;
+///   it is not called for newly created files
+///
+/// # Returns
+/// The patched files with their new contents, and the files that could not be
+/// patched. Hunks are matched the way `apply_exact` matches them, at the position
+/// in their header and after the previous matching hunk.
+pub fn apply_all_collect(
+    patches: &[UnifiedPatch],
+    resolve: impl Fn(&[u8]) -> Option<Vec<u8>>,
+) -> CollectedResults {
+    let mut applied = Vec::new();
+    let mut failed = Vec::new();
+    for patch in patches {
+        let name = if patch.mod_name == DEV_NULL {
+            patch.orig_name.clone()
+        } else {
+            patch.mod_name.clone()
+        };
+        let orig = if patch.orig_name == DEV_NULL {
+            Vec::new()
+        } else if let Some(orig) = resolve(&patch.orig_name) {
+            orig
+        } else {
+            failed.push((name, FileFailure::Missing));
+            continue;
+        };
+        let orig_lines = crate::parse::splitlines(&orig).collect::<Vec<_>>();
+        let mut conflicts = Vec::new();
+        let mut cursor = 0;
+        for hunk in &patch.hunks {
+            match hunk.place_exact(&orig_lines, cursor) {
+                Ok(placement) => cursor = placement.match_end(hunk),
+                Err(conflict) => conflicts.push(conflict),
+            }
+        }
+        if !conflicts.is_empty() {
+            failed.push((name, FileFailure::Conflicts(conflicts)));
+            continue;
+        }
+        match patch.apply_exact(&orig) {
+            Ok(patched) => applied.push((name, patched)),
+            Err(e) => failed.push((name, FileFailure::Error(e))),
+        }
+    }
+    CollectedResults { applied, failed }
+}
+
+#[cfg(test)]
+mod apply_all_collect_tests {
+    use super::{apply_all_collect, FileFailure, Hunk, HunkLine, UnifiedPatch};
+
+    fn replace_patch(name: &[u8], old: &[u8], new: &[u8]) -> UnifiedPatch {
+        let mut patch = UnifiedPatch::new(name.to_vec(), None, name.to_vec(), None);
+        let mut hunk = Hunk::new(1, 1, 1, 1, None);
+        hunk.lines.push(HunkLine::RemoveLine(old.to_vec()));
+        hunk.lines.push(HunkLine::InsertLine(new.to_vec()));
+        patch.hunks.push(hunk);
+        patch
+    }
+
+    #[test]
+    fn test_mixed() {
+        let patches = vec![
+            replace_patch(b"clean", b"a\n", b"b\n"),
+            replace_patch(b"conflict", b"a\n", b"b\n"),
+            replace_patch(b"missing", b"a\n", b"b\n"),
+        ];
+        let resolve = |name: &[u8]| match name {
+            b"clean" => Some(b"a\n".to_vec()),
+            b"conflict" => Some(b"x\n".to_vec()),
+            _ => None,
+        };
+        let results = apply_all_collect(&patches, resolve);
+        assert_eq!(results.applied, vec![(b"clean".to_vec(), b"b\n".to_vec())]);
+        assert_eq!(results.failed.len(), 2);
+        assert_eq!(results.failed[0].0, b"conflict");
+        let FileFailure::Conflicts(conflicts) = &results.failed[0].1 else {
+            panic!("expected conflicts, got {:?}", results.failed[0].1);
+        };
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].line_no, 1);
+        assert_eq!(conflicts[0].orig_line, b"x\n");
+        assert_eq!(results.failed[1].0, b"missing");
+        assert!(matches!(results.failed[1].1, FileFailure::Missing));
+    }
+
+    #[test]
+    fn test_overlapping_hunks() {
+        // Each hunk matches on its own, but the second starts inside the first, so
+        // apply_exact can not apply both
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        for (pos, old) in [(1, "a"), (1, "a")] {
+            let mut hunk = Hunk::new(pos, 1, pos, 1, None);
+            hunk.lines.push(HunkLine::RemoveLine(format!("{}\n", old).into_bytes()));
+            hunk.lines.push(HunkLine::InsertLine(b"b\n".to_vec()));
+            patch.hunks.push(hunk);
+        }
+        let results = apply_all_collect(&[patch], |_| Some(b"a\n".to_vec()));
+        assert!(results.applied.is_empty());
+        assert!(matches!(
+            &results.failed[0].1,
+            FileFailure::Conflicts(conflicts) if conflicts.len() == 1 && conflicts[0].line_no == 1
+        ));
+    }
+}
+
+/// Check that the hunks of a patch map to increasing positions in the modified file
+fn check_hunks_monotonic(patch: &UnifiedPatch) -> Result<(), ApplyError> {
+    let mut last = 0;
+    for (i, hunk) in patch.hunks.iter().enumerate() {
+        let start = hunk.orig_start();
+        let shift = patch.hunks[..i]
+            .iter()
+            .try_fold(0, |shift, prev| Some(shift + prev.shift_to_mod(start)?));
+        last = shift
+            .map(|shift| start as isize + shift)
+            .filter(|&pos| pos >= last)
+            .ok_or_else(|| {
+                ApplyError::Conflict(format!("hunk {} overlaps an earlier hunk", i))
+            })?;
+    }
+    Ok(())
+}
+
+/// Apply several patches to the same file, one after the other
+///
+/// Each patch is applied to the output of the previous one, so its hunk positions
+/// must be relative to the file with the earlier patches applied.
+///
+/// # Returns
+/// The contents after applying all patches. A conflict in any patch aborts the
+/// whole series, and is reported as `ApplyError::SeriesConflict` with the index of
+/// the failing patch.
+pub fn apply_patch_series(orig: &[u8], patches: &[UnifiedPatch]) -> Result<Vec<u8>, ApplyError> {
+    let mut lines = crate::parse::splitlines(orig).map(|l| l.to_vec()).collect::<Vec<_>>();
+    for (patch_index, patch) in patches.iter().enumerate() {
+        lines = check_hunks_monotonic(patch)
+            .and_then(|_| patch.apply_to_lines(&lines))
+            .map_err(|error| ApplyError::SeriesConflict { patch_index, error: Box::new(error) })?;
+    }
+    Ok(lines.concat())
+}
+
+#[cfg(test)]
+mod apply_patch_series_tests {
+    use super::{apply_patch_series, ApplyError, Hunk, HunkLine, UnifiedPatch};
+
+    fn insert_patch(pos: usize, line: &[u8]) -> UnifiedPatch {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        let mut hunk = Hunk::new(pos, 0, pos + 1, 1, None);
+        hunk.lines.push(HunkLine::InsertLine(line.to_vec()));
+        patch.hunks.push(hunk);
+        patch
+    }
+
+    #[test]
+    fn test_series() {
+        let patches = [insert_patch(0, b"first\n"), insert_patch(2, b"after a\n")];
+        assert_eq!(
+            apply_patch_series(b"a\nb\n", &patches).unwrap(),
+            b"first\na\nafter a\nb\n"
+        );
+    }
+
+    #[test]
+    fn test_conflict() {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        let mut hunk = Hunk::new(1, 1, 1, 0, None);
+        hunk.lines.push(HunkLine::RemoveLine(b"a\n".to_vec()));
+        patch.hunks.push(hunk);
+        let patches = [insert_patch(0, b"first\n"), patch];
+        let err = apply_patch_series(b"a\nb\n", &patches).unwrap_err();
+        assert!(matches!(err, ApplyError::SeriesConflict { patch_index: 1, .. }));
+        assert!(err.to_string().starts_with("Patch 1 in series: Conflict: "));
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut patch = insert_patch(2, b"x\n");
+        patch.hunks.extend(insert_patch(0, b"y\n").hunks);
+        let err = apply_patch_series(b"a\nb\n", &[patch]).unwrap_err();
+        assert_eq!(err.to_string(), "Patch 0 in series: Conflict: hunk 1 overlaps an earlier hunk");
+    }
+}
+
+/// Where a hunk was found in the original file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HunkPlacement {
+    /// Index (0-based) in the original of the first line matched by the hunk; this
+    /// excludes any ignored leading context lines
+    pub match_start: usize,
+
+    /// Number of leading context lines that were ignored
+    pub skip_leading: usize,
+
+    /// Number of trailing context lines that were ignored
+    pub skip_trailing: usize,
+
+    /// Offset from the position given in the hunk header
+    pub offset: isize,
+
+    /// Amount of fuzz that was needed
+    pub fuzz: usize,
+}
+
+impl HunkPlacement {
+    /// Index (0-based) in the original just past the last line matched by the hunk
+    pub fn match_end(&self, hunk: &Hunk) -> usize {
+        self.match_start
+            + hunk.lines.iter().filter(|l| !matches!(l, HunkLine::InsertLine(_))).count()
+            - self.skip_leading
+            - self.skip_trailing
+    }
+
+    /// Apply `hunk` at this placement
+    ///
+    /// The original lines from `cursor` up to the hunk are passed through first,
+    /// followed by the lines the hunk produces; ignored context lines are passed
+    /// through from the original. Each output line is paired with the (0-based)
+    /// index of the original line it came from, or `None` if the hunk inserted it.
+    ///
+    /// # Returns
+    /// The index (0-based) of the first original line after the hunk, to use as
+    /// `cursor` for the next hunk
+    pub fn apply<'a>(
+        &self,
+        hunk: &'a Hunk,
+        orig_lines: &[&'a [u8]],
+        cursor: usize,
+        output: &mut Vec<(Option<usize>, &'a [u8])>,
+    ) -> usize {
+        output.extend((cursor..self.match_start).map(|i| (Some(i), orig_lines[i])));
+        let mut pos = self.match_start;
+        for line in &hunk.lines[self.skip_leading..hunk.lines.len() - self.skip_trailing] {
+            match line {
+                HunkLine::ContextLine(_) => {
+                    output.push((Some(pos), orig_lines[pos]));
+                    pos += 1;
+                }
+                HunkLine::RemoveLine(_) => pos += 1,
+                HunkLine::InsertLine(bytes) => output.push((None, bytes)),
+            }
+        }
+        pos
+    }
+}
+
+/// Check whether the lines of a hunk that are present in the original match starting
+/// at `start`, ignoring the given number of leading and trailing context lines
+fn hunk_matches_at(
+    hunk: &Hunk,
+    orig_lines: &[&[u8]],
+    start: usize,
+    skip_leading: usize,
+    skip_trailing: usize,
+) -> bool {
+    let expected = hunk
+        .lines
+        .iter()
+        .filter_map(|line| match line {
+            HunkLine::ContextLine(bytes) | HunkLine::RemoveLine(bytes) => Some(bytes.as_slice()),
+            HunkLine::InsertLine(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let expected = &expected[skip_leading..expected.len() - skip_trailing];
+    start + expected.len() <= orig_lines.len()
+        && expected
+            .iter()
+            .zip(&orig_lines[start..])
+            .all(|(expected, actual)| expected == actual)
+}
+
+/// Find where a hunk applies in the original, GNU patch style
+///
+/// For each fuzz level (from 0 up to `max_fuzz`), up to that many leading and
+/// trailing context lines are ignored, and positions are tried at increasing
+/// distance from where the hunk is expected to be.
+///
+/// # Arguments
+/// * `hunk` - The hunk to place
+/// * `orig_lines` - Lines of the original file
+/// * `min_start` - First line the hunk may touch; used to prevent hunks from overlapping
+/// * `origin_offset` - Offset at which to start searching, typically that of the previous hunk
+/// * `max_fuzz` - Maximum number of context lines to ignore at either end
+/// * `max_offset` - Maximum distance from `origin_offset` to search, or `None` for the whole file
+pub(crate) fn locate_hunk(
+    hunk: &Hunk,
+    orig_lines: &[&[u8]],
+    min_start: usize,
+    origin_offset: isize,
+    max_fuzz: usize,
+    max_offset: Option<usize>,
+) -> Option<HunkPlacement> {
+    let leading_context = hunk
+        .lines
+        .iter()
+        .take_while(|l| matches!(l, HunkLine::ContextLine(_)))
+        .count();
+    let trailing_context = hunk
+        .lines
+        .iter()
+        .rev()
+        .take_while(|l| matches!(l, HunkLine::ContextLine(_)))
+        .count();
+    let orig_count = hunk.lines.iter().filter(|l| !matches!(l, HunkLine::InsertLine(_))).count();
+    let declared = hunk.orig_start() as isize;
+    let origin = declared + origin_offset;
+    let max_distance = max_offset.unwrap_or(orig_lines.len() + orig_count);
+
+    for fuzz in 0..=max_fuzz {
+        let skip_leading = fuzz.min(leading_context);
+        let skip_trailing = fuzz.min(trailing_context).min(orig_count - skip_leading);
+        let matched = (orig_count - skip_leading - skip_trailing) as isize;
+        for distance in 0..=max_distance as isize {
+            for candidate in [origin + distance, origin - distance] {
+                // The ignored leading lines may fall before the start of the file
+                let match_start = candidate + skip_leading as isize;
+                if match_start < min_start as isize
+                    || match_start + matched > orig_lines.len() as isize
+                {
+                    continue;
+                }
+                let match_start = match_start as usize;
+                if hunk_matches_at(hunk, orig_lines, match_start, skip_leading, skip_trailing) {
+                    return Some(HunkPlacement {
+                        match_start,
+                        skip_leading,
+                        skip_trailing,
+                        offset: candidate - declared,
+                        fuzz,
+                    });
+                }
+                if distance == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The lines "line N" for each N in `range`, for tests of `locate_hunk` and its callers
+#[cfg(test)]
+fn numbered_lines(range: std::ops::RangeInclusive<usize>) -> String {
+    range.map(|i| format!("line {}\n", i)).collect()
+}
+
+/// A hunk against `numbered_lines` that replaces line `removed` with "changed N"
+#[cfg(test)]
+fn numbered_hunk(orig_pos: usize, context_before: &[usize], removed: usize, context_after: &[usize]) -> Hunk {
+    let range = context_before.len() + 1 + context_after.len();
+    let mut hunk = Hunk::new(orig_pos, range, orig_pos, range, None);
+    for i in context_before {
+        hunk.lines.push(HunkLine::ContextLine(format!("line {}\n", i).into_bytes()));
+    }
+    hunk.lines.push(HunkLine::RemoveLine(format!("line {}\n", removed).into_bytes()));
+    hunk.lines.push(HunkLine::InsertLine(format!("changed {}\n", removed).into_bytes()));
+    for i in context_after {
+        hunk.lines.push(HunkLine::ContextLine(format!("line {}\n", i).into_bytes()));
+    }
+    hunk
+}
+
+/// Result of checking a single hunk in `dry_run`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HunkReport {
+    /// Whether the hunk would apply
+    pub applies: bool,
+
+    /// Offset, in lines, from the position given in the hunk header
+    pub offset: isize,
+
+    /// Number of context lines that had to be ignored
+    pub fuzz: usize,
+}
+
+/// Result of `dry_run`: what would happen to each hunk of a patch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DryRunReport {
+    /// One entry per hunk, in patch order
+    pub hunks: Vec<HunkReport>,
+}
+
+impl DryRunReport {
+    /// Whether all hunks would apply
+    pub fn applies(&self) -> bool {
+        self.hunks.iter().all(|hunk| hunk.applies)
+    }
+}
+
+/// Check how a patch would apply to a file, without producing any output
+///
+/// Like `patch --dry-run`, hunks are allowed to apply at an offset from their
+/// stated position, and with up to `fuzz` context lines ignored at either end.
+pub fn dry_run(patch: &UnifiedPatch, orig: &[u8], fuzz: usize) -> DryRunReport {
+    let orig_lines = crate::parse::splitlines(orig).collect::<Vec<_>>();
+    let mut min_start = 0;
+    let mut last_offset = 0;
+    let hunks = patch
+        .hunks
+        .iter()
+        .map(|hunk| match locate_hunk(hunk, &orig_lines, min_start, last_offset, fuzz, None) {
+            Some(placement) => {
+                min_start = placement.match_end(hunk);
+                last_offset = placement.offset;
+                HunkReport {
+                    applies: true,
+                    offset: placement.offset,
+                    fuzz: placement.fuzz,
+                }
+            }
+            None => HunkReport {
+                applies: false,
+                offset: 0,
+                fuzz: 0,
+            },
+        })
+        .collect();
+    DryRunReport { hunks }
+}
+
+#[cfg(test)]
+mod dry_run_tests {
+    use super::{dry_run, numbered_hunk as hunk, numbered_lines, HunkLine, HunkReport, UnifiedPatch};
+
+    #[test]
+    fn test_offset() {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        // Applies exactly
+        patch.hunks.push(hunk(2, &[2], 3, &[4]));
+        // Claims to be at line 10, but the lines are at 12
+        patch.hunks.push(hunk(10, &[12], 13, &[14]));
+
+        let report = dry_run(&patch, numbered_lines(1..=20).as_bytes(), 0);
+        assert_eq!(
+            report.hunks,
+            vec![
+                HunkReport { applies: true, offset: 0, fuzz: 0 },
+                HunkReport { applies: true, offset: 2, fuzz: 0 },
+            ]
+        );
+        assert!(report.applies());
+    }
+
+    #[test]
+    fn test_fuzz() {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        // The leading context line no longer matches
+        let mut h = hunk(4, &[4, 5], 6, &[7]);
+        h.lines[0] = HunkLine::ContextLine(b"something else\n".to_vec());
+        patch.hunks.push(h);
+
+        assert!(!dry_run(&patch, numbered_lines(1..=10).as_bytes(), 0).applies());
+        assert_eq!(
+            dry_run(&patch, numbered_lines(1..=10).as_bytes(), 1).hunks,
+            vec![HunkReport { applies: true, offset: 0, fuzz: 1 }]
+        );
+    }
+
+    #[test]
+    fn test_does_not_apply() {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        patch.hunks.push(hunk(2, &[2], 30, &[4]));
+        let report = dry_run(&patch, numbered_lines(1..=10).as_bytes(), 2);
+        assert!(!report.applies());
+        assert!(!report.hunks[0].applies);
+    }
+}
+
+/// How a hunk was applied by `UnifiedPatch::apply_fuzzy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppliedHunk {
+    /// Offset, in lines, from the position given in the hunk header
+    pub offset: isize,
+
+    /// Number of context lines that had to be ignored
+    pub fuzz: usize,
+}
+
+impl UnifiedPatch {
+    /// Apply this patch, allowing hunks to be moved and some context to be ignored
+    ///
+    /// Like GNU patch's fuzz factor, up to `max_fuzz` leading and trailing context
+    /// lines of each hunk may be ignored, and the hunk may apply up to `max_fuzz`
+    /// lines away from where it is expected: the position in its header, adjusted by
+    /// the offset of the previous hunk. Hunks may not overlap.
+    ///
+    /// # Returns
+    /// The patched contents and how each hunk was applied, or the conflict for the
+    /// first hunk that could not be placed
+    pub fn apply_fuzzy(
+        &self,
+        orig: &[u8],
+        max_fuzz: usize,
+    ) -> Result<(Vec<u8>, Vec<AppliedHunk>), crate::parse::PatchConflict> {
+        let orig_lines = crate::parse::splitlines(orig).collect::<Vec<_>>();
+        let mut output = Vec::new();
+        let mut applied = Vec::with_capacity(self.hunks.len());
+        let mut cursor = 0;
+        let mut last_offset = 0;
+        for hunk in &self.hunks {
+            let placement =
+                locate_hunk(hunk, &orig_lines, cursor, last_offset, max_fuzz, Some(max_fuzz))
+                    .ok_or_else(|| {
+                        let start = (hunk.orig_start() as isize + last_offset)
+                            .clamp(cursor as isize, orig_lines.len() as isize)
+                            as usize;
+                        hunk.find_conflict(&orig_lines, start).unwrap_or_else(|| {
+                            crate::parse::PatchConflict {
+                                line_no: start + 1,
+                                orig_line: orig_lines.get(start).map(|l| l.to_vec()).unwrap_or_default(),
+                                patch_line: Vec::new(),
+                            }
+                        })
+                    })?;
+            cursor = placement.apply(hunk, &orig_lines, cursor, &mut output);
+            last_offset = placement.offset;
+            applied.push(AppliedHunk {
+                offset: placement.offset,
+                fuzz: placement.fuzz,
+            });
+        }
+        let output = output.into_iter().map(|(_, line)| line).chain(orig_lines[cursor..].iter().copied());
+        Ok((output.collect::<Vec<_>>().concat(), applied))
+    }
+}
+
+#[cfg(test)]
+mod apply_fuzzy_tests {
+    use super::{numbered_hunk as hunk, numbered_lines as lines, AppliedHunk, HunkLine, UnifiedPatch};
+
+    #[test]
+    fn test_offset_and_fuzz() {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        patch.hunks.push(hunk(3, &[3, 4], 5, &[6, 7]));
+        // Written against a file that had two fewer lines, and with a stale leading
+        // context line
+        let mut second = hunk(10, &[12, 13], 14, &[15, 16]);
+        second.lines[0] = HunkLine::ContextLine(b"stale\n".to_vec());
+        patch.hunks.push(second);
+
+        let orig = lines(1..=20);
+        let (patched, applied) = patch.apply_fuzzy(orig.as_bytes(), 2).unwrap();
+        assert_eq!(
+            String::from_utf8(patched).unwrap(),
+            orig.replace("line 5\n", "changed 5\n").replace("line 14\n", "changed 14\n")
+        );
+        assert_eq!(
+            applied,
+            vec![
+                AppliedHunk { offset: 0, fuzz: 0 },
+                AppliedHunk { offset: 2, fuzz: 1 },
+            ]
+        );
+        assert!(patch.apply_fuzzy(orig.as_bytes(), 0).is_err());
+    }
+
+    #[test]
+    fn test_overlapping_hunks() {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        patch.hunks.push(hunk(2, &[2, 3], 4, &[5, 6]));
+        // Shifted back by one line, this hunk would apply to lines the first hunk
+        // already changed
+        patch.hunks.push(hunk(6, &[5], 6, &[7]));
+
+        let conflict = patch.apply_fuzzy(lines(1..=20).as_bytes(), 2).unwrap_err();
+        assert_eq!(conflict.line_no, 7);
+        assert_eq!(conflict.patch_line, b"line 5\n");
+    }
+
+    #[test]
+    fn test_conflict() {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        patch.hunks.push(hunk(2, &[2], 30, &[4]));
+        let conflict = patch.apply_fuzzy(lines(1..=10).as_bytes(), 1).unwrap_err();
+        assert_eq!(conflict.line_no, 3);
+        assert_eq!(conflict.orig_line, b"line 3\n");
+        assert_eq!(conflict.patch_line, b"line 30\n");
+    }
+}
+
+/// Where a line in the output of an applied patch came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provenance {
+    /// The line was passed through from the original file; carries its (1-based) line number
+    Original(usize),
+
+    /// The line was inserted by the patch
+    Patch,
+}
+
+impl UnifiedPatch {
+    /// Apply this patch, recording for each output line where it came from
+    ///
+    /// Like `apply_exact`, this requires the original to match the patch exactly.
+    pub fn apply_with_provenance(
+        &self,
+        orig: &[u8],
+    ) -> Result<Vec<(Provenance, Vec<u8>)>, ApplyError> {
+        let orig_lines = crate::parse::splitlines(orig).collect::<Vec<_>>();
+        let mut output = Vec::new();
+        let mut cursor = 0;
+        for (i, hunk) in self.hunks.iter().enumerate() {
+            let placement = hunk
+                .place_exact(&orig_lines, cursor)
+                .map_err(|conflict| ApplyError::from_conflict(i, conflict))?;
+            cursor = placement.apply(hunk, &orig_lines, cursor, &mut output);
+        }
+        output.extend((cursor..orig_lines.len()).map(|i| (Some(i), orig_lines[i])));
+        Ok(output
+            .into_iter()
+            .map(|(orig_index, line)| {
+                let provenance = match orig_index {
+                    Some(i) => Provenance::Original(i + 1),
+                    None => Provenance::Patch,
+                };
+                (provenance, line.to_vec())
+            })
+            .collect())
+    }
 }
 
-impl std::fmt::Display for ApplyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::Conflict(reason) => write!(f, "Conflict: {}", reason),
-            Self::Unapplyable => write!(f, "Patch unapplyable"),
+impl UnifiedPatch {
+    /// Apply this patch, and generate a fresh diff between the original and the result
+    ///
+    /// If the patch applied cleanly, the returned diff has the same effect as this patch;
+    /// this makes it useful as a self-check, or to normalize a patch.
+    pub fn apply_and_diff(&self, orig: &[u8]) -> Result<(Vec<u8>, UnifiedPatch), ApplyError> {
+        let patched = self.apply_exact(orig)?;
+        let mut diff = crate::diff::diff_unified(&self.orig_name, &self.mod_name, orig, &patched);
+        diff.orig_ts = self.orig_ts.clone();
+        diff.mod_ts = self.mod_ts.clone();
+        Ok((patched, diff))
+    }
+
+    /// Combine this patch with one that follows it into a single patch
+    ///
+    /// `self` is applied to `base`, then `next` is applied to the result; the returned
+    /// patch goes straight from `base` to the final contents, using the old name of
+    /// `self` and the new name of `next`.
+    ///
+    /// # Arguments
+    /// * `base` - The contents `self` applies to
+    /// * `next` - The patch to apply after `self`
+    pub fn compose(&self, base: &[u8], next: &UnifiedPatch) -> Result<UnifiedPatch, ApplyError> {
+        let intermediate = self.apply_exact(base)?;
+        let result = next.apply_exact(&intermediate)?;
+        let mut composed = crate::diff::diff_unified(&self.orig_name, &next.mod_name, base, &result);
+        composed.orig_ts = self.orig_ts.clone();
+        composed.mod_ts = next.mod_ts.clone();
+        Ok(composed)
+    }
+
+    /// Find blocks of lines that were moved from one hunk to another
+    ///
+    /// A run of consecutive removed lines in one hunk that is identical to a run of
+    /// consecutive inserted lines in another hunk is reported as a move, rather than
+    /// as a deletion and an unrelated addition. Each inserted run is matched at most
+    /// once.
+    pub fn detect_moves(&self) -> Vec<MovedBlock> {
+        // Maximal runs of removed (or inserted) lines: hunk index, first line number
+        // and the lines themselves
+        let runs = |removed: bool| {
+            let mut runs: Vec<(usize, usize, Vec<Vec<u8>>)> = Vec::new();
+            for (i, hunk) in self.hunks.iter().enumerate() {
+                let mut in_run = false;
+                for (orig_line, mod_line, line) in hunk.numbered_lines() {
+                    let (line_no, bytes) = match (removed, line) {
+                        (true, HunkLine::RemoveLine(bytes)) => (orig_line, bytes),
+                        (false, HunkLine::InsertLine(bytes)) => (mod_line, bytes),
+                        _ => {
+                            in_run = false;
+                            continue;
+                        }
+                    };
+                    match runs.last_mut() {
+                        Some(run) if in_run => run.2.push(bytes.clone()),
+                        _ => runs.push((i, line_no.unwrap(), vec![bytes.clone()])),
+                    }
+                    in_run = true;
+                }
+            }
+            runs
+        };
+        let mut inserted = runs(false).into_iter().map(Some).collect::<Vec<_>>();
+        let mut moves = Vec::new();
+        for (hunk, from, lines) in runs(true) {
+            let found = inserted.iter_mut().find(|run| {
+                matches!(run, Some((other, _, other_lines)) if *other != hunk && *other_lines == lines)
+            });
+            if let Some((_, to, _)) = found.and_then(|run| run.take()) {
+                moves.push(MovedBlock { from, to, lines });
+            }
         }
+        moves
+    }
+
+    /// Apply this patch, reporting progress as each hunk is applied
+    ///
+    /// # Arguments
+    /// * `orig` - The original contents
+    /// * `progress` - Called with the (0-based) index of each hunk once it has been
+    ///   applied, and the total number of hunks
+    pub fn apply_with_progress<F: FnMut(usize, usize)>(
+        &self,
+        orig: &[u8],
+        mut progress: F,
+    ) -> Result<Vec<u8>, ApplyError> {
+        let orig_lines = crate::parse::splitlines(orig).map(|l| l.to_vec()).collect::<Vec<_>>();
+        let mut output = Vec::new();
+        let mut cursor = 0;
+        for (i, hunk) in self.hunks.iter().enumerate() {
+            let (lines, next) = hunk
+                .apply_at(&orig_lines, cursor)
+                .map_err(|e| ApplyError::from_conflict(i, e))?;
+            output.extend(lines);
+            cursor = next;
+            progress(i, self.hunks.len());
+        }
+        output.extend_from_slice(&orig_lines[cursor..]);
+        Ok(output.concat())
+    }
+
+    /// Serialize hunks that failed to apply in the format of a `.rej` file
+    ///
+    /// The `---`/`+++` header of this patch is repeated, followed by the failed
+    /// hunks with their original headers and lines.
+    pub fn reject_file(&self, failed_hunks: &[Hunk]) -> Vec<u8> {
+        let mut reject = UnifiedPatch::new(
+            self.orig_name.clone(),
+            self.orig_ts.clone(),
+            self.mod_name.clone(),
+            self.mod_ts.clone(),
+        );
+        reject.crlf = self.crlf;
+        reject.hunks = failed_hunks.to_vec();
+        reject.as_bytes()
     }
 }
 
-impl std::error::Error for ApplyError {}
+#[cfg(test)]
+mod hunk_conflict_tests {
+    use super::{ApplyError, ApplyOptions, Hunk, HunkLine, Patch, UnifiedPatch};
 
-/// A patch of some sort
-pub trait Patch {
-    /// Old file name
-    fn oldname(&self) -> &[u8];
+    fn patch() -> UnifiedPatch {
+        let mut patch = UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        for pos in [2, 6] {
+            let mut hunk = Hunk::new(pos, 1, pos, 1, None);
+            hunk.lines.push(HunkLine::RemoveLine(format!("{}\n", pos).into_bytes()));
+            hunk.lines.push(HunkLine::InsertLine(b"changed\n".to_vec()));
+            patch.hunks.push(hunk);
+        }
+        patch
+    }
 
-    /// New file name
-    fn newname(&self) -> &[u8];
+    #[test]
+    fn test_second_hunk() {
+        let err = patch().apply_exact(b"1\n2\n3\n4\n5\nsix\n7\n").unwrap_err();
+        assert!(matches!(
+            &err,
+            ApplyError::HunkConflict { hunk_index: 1, line_no: 6, orig_line, patch_line }
+                if orig_line == b"six\n" && patch_line == b"6\n"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Conflict: Patch conflict at orig line 6: orig: \"six\\n\", patch: \"6\\n\""
+        );
+    }
 
-    fn apply_exact(&self, orig: &[u8]) -> Result<Vec<u8>, ApplyError>;
-}
+    #[test]
+    fn test_apply_to_lines() {
+        let lines = (1..=7).map(|i| format!("{}\n", i).into_bytes()).collect::<Vec<_>>();
+        let patched = patch().apply_to_lines(&lines).unwrap();
+        assert_eq!(patched.len(), 7);
+        assert_eq!(patched[1], b"changed\n");
+        assert_eq!(patched[5], b"changed\n");
+        assert_eq!(patched.concat(), patch().apply_exact(&lines.concat()).unwrap());
 
-/// A binary patch
-pub struct BinaryPatch(pub Vec<u8>, pub Vec<u8>);
+        let mut lines = lines;
+        lines[5] = b"six\n".to_vec();
+        assert!(matches!(
+            patch().apply_to_lines(&lines),
+            Err(ApplyError::HunkConflict { hunk_index: 1, line_no: 6, .. })
+        ));
+    }
 
-impl Patch for BinaryPatch {
-    fn oldname(&self) -> &[u8] {
-        &self.0
+    #[test]
+    fn test_sorted_hunks() {
+        let mut patch = patch();
+        patch.hunks.reverse();
+        let options = ApplyOptions { sort_hunks: true, ..Default::default() };
+        let err = patch.apply_with_options(b"1\ntwo\n3\n4\n5\n6\n7\n", &options).unwrap_err();
+        // Reported against the position of the hunk in the patch, not the sorted order
+        assert!(matches!(err, ApplyError::HunkConflict { hunk_index: 1, line_no: 2, .. }));
     }
 
-    fn newname(&self) -> &[u8] {
-        &self.1
+    #[test]
+    fn test_apply_best_effort() {
+        let patch = patch();
+        let result = patch.apply(b"1\ntwo\n3\n4\n5\n6\n7\n");
+        assert!(!result.is_clean());
+        assert_eq!(result.output, b"1\ntwo\n3\n4\n5\nchanged\n7\n");
+        assert_eq!(result.rejected, vec![patch.hunks[0].clone()]);
     }
 
-    fn apply_exact(&self, _orig: &[u8]) -> Result<Vec<u8>, ApplyError> {
-        Err(ApplyError::Unapplyable)
+    #[test]
+    fn test_apply_binary() {
+        let result = super::BinaryPatch(b"a".to_vec(), b"a".to_vec()).apply(b"data");
+        assert!(result.unapplyable);
+        assert_eq!(result.output, b"data");
+    }
+
+    #[test]
+    fn test_reject_file() {
+        let patch = patch();
+        let result = patch.apply(b"1\ntwo\n3\n4\n5\n6\n7\n");
+        let reject = patch.reject_file(&result.rejected);
+        assert_eq!(reject, b"--- a\n+++ a\n@@ -2 +2 @@\n-2\n+changed\n");
+        let parsed = UnifiedPatch::parse_patch(crate::parse::splitlines(&reject), false).unwrap();
+        assert_eq!(parsed.hunks, result.rejected);
     }
 }
 
-/// A unified diff style patch
-pub struct UnifiedPatch {
-    /// Name of the original file
-    pub orig_name: Vec<u8>,
+#[cfg(test)]
+mod reverse_tests {
+    use super::{Patch, UnifiedPatch};
 
-    /// Timestamp for the original file
-    pub orig_ts: Option<Vec<u8>>,
+    #[test]
+    fn test_reverse() {
+        let data = b"--- a/foo\t2009-10-14 19:49:59 +0000\n+++ b/foo\n@@ -1,3 +1,2 @@ fn main()\n a\n-b\n-c\n+C\n@@ -10,0 +11 @@\n+new\n";
+        let patch = UnifiedPatch::parse_patch(crate::parse::splitlines(data), false).unwrap();
+        let reversed = patch.reverse();
+        assert_eq!(
+            reversed.as_bytes(),
+            b"--- b/foo\n+++ a/foo\t2009-10-14 19:49:59 +0000\n@@ -1,2 +1,3 @@ fn main()\n a\n+b\n+c\n-C\n@@ -11 +10,0 @@\n-new\n"
+        );
+        assert_eq!(reversed.reverse(), patch);
+        assert_eq!(reversed.reverse().as_bytes(), data);
+    }
 
-    /// Name of the modified file
-    pub mod_name: Vec<u8>,
+    #[test]
+    fn test_apply_reverse() {
+        let orig = include_bytes!("../test_patches_data/orig-2");
+        let modified = include_bytes!("../test_patches_data/mod-2");
+        let data = include_bytes!("../test_patches_data/diff-2");
+        let patch = UnifiedPatch::parse_patch(crate::parse::splitlines(data), false).unwrap();
+        assert_eq!(patch.apply_exact(orig).unwrap(), modified);
+        assert_eq!(patch.apply_reverse(modified).unwrap(), orig);
+    }
+}
 
-    /// Timestamp for the modified file
-    pub mod_ts: Option<Vec<u8>>,
+#[cfg(test)]
+mod apply_and_diff_tests {
+    use super::{Hunk, HunkLine, Patch, UnifiedPatch};
 
-    /// List of hunks
-    pub hunks: Vec<Hunk>,
-}
+    #[test]
+    fn test_apply_and_diff() {
+        let orig = b"line 1\nline 2\nline 3\nline 4\nline 5\n";
+        let mut patch = UnifiedPatch::new(b"foo".to_vec(), None, b"foo".to_vec(), None);
+        let mut hunk = Hunk::new(1, 5, 1, 5, None);
+        hunk.lines.extend([
+            HunkLine::ContextLine(b"line 1\n".to_vec()),
+            HunkLine::ContextLine(b"line 2\n".to_vec()),
+            HunkLine::RemoveLine(b"line 3\n".to_vec()),
+            HunkLine::InsertLine(b"line three\n".to_vec()),
+            HunkLine::ContextLine(b"line 4\n".to_vec()),
+            HunkLine::ContextLine(b"line 5\n".to_vec()),
+        ]);
+        patch.hunks.push(hunk);
 
-impl UnifiedPatch {
-    pub fn new(
-        orig_name: Vec<u8>,
-        orig_ts: Option<Vec<u8>>,
-        mod_name: Vec<u8>,
-        mod_ts: Option<Vec<u8>>,
-    ) -> Self {
-        Self {
-            orig_name,
-            orig_ts,
-            mod_name,
-            mod_ts,
-            hunks: Vec::new(),
-        }
+        let (patched, diff) = patch.apply_and_diff(orig).unwrap();
+        assert_eq!(patched, b"line 1\nline 2\nline three\nline 4\nline 5\n");
+        assert_eq!(diff.apply_exact(orig).unwrap(), patched);
+        assert_eq!(diff.as_bytes(), patch.as_bytes());
     }
-}
 
-impl Patch for UnifiedPatch {
-    fn oldname(&self) -> &[u8] {
-        &self.orig_name
+    #[test]
+    fn test_compose() {
+        let base = b"a\nb\nc\nd\n";
+        let first = crate::diff::diff_unified(b"a/foo", b"b/foo", base, b"a\nB\nc\nd\n");
+        let second =
+            crate::diff::diff_unified(b"a/foo", b"b/foo", b"a\nB\nc\nd\n", b"a\nB\nc\nD\ne\n");
+        let composed = first.compose(base, &second).unwrap();
+        assert_eq!(composed.apply_exact(base).unwrap(), b"a\nB\nc\nD\ne\n");
+        assert_eq!(composed.hunk_count(), 1);
+
+        // The second patch does not apply to the original
+        assert!(second.compose(base, &first).is_err());
     }
 
-    fn newname(&self) -> &[u8] {
-        &self.mod_name
+    #[test]
+    fn test_detect_moves() {
+        let orig = (1..=20).map(|i| format!("{}\n", i)).collect::<String>();
+        // Move lines 3-5 to after line 17
+        let modified = (1..=20)
+            .filter(|i| !(3..=5).contains(i))
+            .flat_map(|i| if i == 17 { vec![17, 3, 4, 5] } else { vec![i] })
+            .map(|i| format!("{}\n", i))
+            .collect::<String>();
+        let patch = crate::diff::diff_unified(b"a", b"b", orig.as_bytes(), modified.as_bytes());
+        assert_eq!(patch.hunks.len(), 2);
+        assert_eq!(
+            patch.detect_moves(),
+            vec![super::MovedBlock {
+                from: 3,
+                to: 15,
+                lines: vec![b"3\n".to_vec(), b"4\n".to_vec(), b"5\n".to_vec()],
+            }]
+        );
     }
 
-    fn apply_exact(&self, orig: &[u8]) -> Result<Vec<u8>, ApplyError> {
-        let orig_lines = crate::parse::splitlines(orig).map(|l| l.to_vec());
-        let lines = crate::parse::iter_exact_patched_from_hunks(
-            orig_lines,
-            self.hunks.clone().into_iter()).collect::<Result<Vec<Vec<u8>>, crate::parse::PatchConflict>>()
-            .map_err(|e| ApplyError::Conflict(e.to_string()))?;
-        Ok(lines.concat())
+    #[test]
+    fn test_detect_moves_same_hunk() {
+        let patch = crate::diff::diff_unified(b"a", b"b", b"a\nb\nc\n", b"b\nc\na\n");
+        assert_eq!(patch.detect_moves(), vec![]);
+    }
+
+    #[test]
+    fn test_apply_with_progress() {
+        let orig = (1..=20).map(|i| format!("{}\n", i)).collect::<String>();
+        let modified = orig.replace("\n2\n", "\ntwo\n").replace("\n18\n", "\neighteen\n");
+        let patch = crate::diff::diff_unified(b"a", b"b", orig.as_bytes(), modified.as_bytes());
+        assert_eq!(patch.hunks.len(), 2);
+
+        let mut calls = Vec::new();
+        let patched = patch
+            .apply_with_progress(orig.as_bytes(), |i, total| calls.push((i, total)))
+            .unwrap();
+        assert_eq!(patched, modified.as_bytes());
+        assert_eq!(calls, vec![(0, 2), (1, 2)]);
+    }
+}
+
+#[cfg(test)]
+mod provenance_tests {
+    use super::{ApplyError, Hunk, HunkLine, Patch, Provenance, UnifiedPatch};
+
+    #[test]
+    fn test_apply_with_provenance() {
+        let mut patch = UnifiedPatch::new(b"foo".to_vec(), None, b"foo".to_vec(), None);
+        let mut hunk = Hunk::new(2, 2, 2, 3, None);
+        hunk.lines.extend([
+            HunkLine::ContextLine(b"line 2\n".to_vec()),
+            HunkLine::InsertLine(b"new line\n".to_vec()),
+            HunkLine::ContextLine(b"line 3\n".to_vec()),
+        ]);
+        patch.hunks.push(hunk);
+
+        let result = patch
+            .apply_with_provenance(b"line 1\nline 2\nline 3\nline 4\n")
+            .unwrap();
+        assert_eq!(
+            result,
+            vec![
+                (Provenance::Original(1), b"line 1\n".to_vec()),
+                (Provenance::Original(2), b"line 2\n".to_vec()),
+                (Provenance::Patch, b"new line\n".to_vec()),
+                (Provenance::Original(3), b"line 3\n".to_vec()),
+                (Provenance::Original(4), b"line 4\n".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_with_provenance_conflict() {
+        let mut patch = UnifiedPatch::new(b"foo".to_vec(), None, b"foo".to_vec(), None);
+        let mut hunk = Hunk::new(1, 1, 1, 0, None);
+        hunk.lines.push(HunkLine::RemoveLine(b"other\n".to_vec()));
+        patch.hunks.push(hunk);
+        let err = patch.apply_with_provenance(b"line 1\n").unwrap_err();
+        assert!(matches!(
+            err,
+            ApplyError::HunkConflict { hunk_index: 0, line_no: 1, .. }
+        ));
+        assert_eq!(err.to_string(), patch.apply_exact(b"line 1\n").unwrap_err().to_string());
     }
 }
 
@@ -188,6 +2381,46 @@ impl std::fmt::Display for MalformedHunkHeader {
 
 impl std::error::Error for MalformedHunkHeader {}
 
+/// A hunk header tail that can not be represented in a patch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidTail(pub Vec<u8>);
+
+impl std::fmt::Display for InvalidTail {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Invalid hunk header tail: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTail {}
+
+/// Mismatch between the ranges in a hunk header and the lines of the hunk
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HunkValidationError {
+    /// Original range given in the header
+    pub expected_orig: usize,
+
+    /// Number of context and removed lines
+    pub actual_orig: usize,
+
+    /// Modified range given in the header
+    pub expected_mod: usize,
+
+    /// Number of context and inserted lines
+    pub actual_mod: usize,
+}
+
+impl std::fmt::Display for HunkValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Hunk line counts do not match its ranges: original {} (expected {}), modified {} (expected {})",
+            self.actual_orig, self.expected_orig, self.actual_mod, self.expected_mod
+        )
+    }
+}
+
+impl std::error::Error for HunkValidationError {}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Hunk {
     pub orig_pos: usize,
@@ -217,11 +2450,18 @@ impl Hunk {
     }
 
     pub fn from_header(line: &[u8]) -> Result<Self, MalformedHunkHeader> {
-        let re = Regex::new(r"\@\@ ([^@]*) \@\@( (.*))?\n").unwrap();
+        // The space before the closing @@ is optional, as some emitters leave it out,
+        // and the newline may be missing at the end of a truncated patch
+        let re = Regex::new(r"\@\@ ([^@]*)\@\@( (.*))?\n?$").unwrap();
         let captures = re
             .captures(line)
             .ok_or_else(|| MalformedHunkHeader("Does not match format.", line.to_vec()))?;
-        let (orig, modi) = match captures[1].split(|b| *b == b' ').collect::<Vec<&[u8]>>()[..] {
+        // Some tools pad the ranges with extra spaces
+        let (orig, modi) = match captures[1]
+            .split(|b| *b == b' ')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<&[u8]>>()[..]
+        {
             [orig, modi] => Ok((orig, modi)),
             _ => {
                 return Err(MalformedHunkHeader(
@@ -249,6 +2489,215 @@ impl Hunk {
         Ok(Self::new(orig_pos, orig_range, mod_pos, mod_range, tail))
     }
 
+    /// Index (0-based) of the first original line covered by this hunk
+    ///
+    /// A hunk that only inserts lines (and so has an empty original range) inserts
+    /// them after line `orig_pos`.
+    pub(crate) fn orig_start(&self) -> usize {
+        let pure_insert = self.lines.iter().all(|line| matches!(line, HunkLine::InsertLine(_)));
+        if self.orig_range == 0 && pure_insert {
+            self.orig_pos
+        } else {
+            self.orig_pos.saturating_sub(1)
+        }
+    }
+
+    /// Find the first line where this hunk does not match the original, if it were
+    /// applied at (0-based) line `start`
+    pub(crate) fn find_conflict(
+        &self,
+        orig_lines: &[&[u8]],
+        start: usize,
+    ) -> Option<crate::parse::PatchConflict> {
+        let mut pos = start;
+        for line in &self.lines {
+            match line {
+                HunkLine::ContextLine(bytes) | HunkLine::RemoveLine(bytes) => {
+                    if orig_lines.get(pos) != Some(&bytes.as_slice()) {
+                        return Some(crate::parse::PatchConflict {
+                            line_no: pos + 1,
+                            orig_line: orig_lines.get(pos).map(|l| l.to_vec()).unwrap_or_default(),
+                            patch_line: bytes.clone(),
+                        });
+                    }
+                    pos += 1;
+                }
+                HunkLine::InsertLine(_) => {}
+            }
+        }
+        None
+    }
+
+    /// Check each context and removed line of this hunk against the original file
+    ///
+    /// The lines are compared at the position given in the hunk header, without
+    /// searching for an offset.
+    ///
+    /// # Returns
+    /// For each context or removed line, the (1-based) original line number it was
+    /// compared with and whether it matched
+    pub fn context_matches(&self, orig: &[u8]) -> Vec<(usize, bool)> {
+        let orig_lines = crate::parse::splitlines(orig).collect::<Vec<_>>();
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::ContextLine(bytes) | HunkLine::RemoveLine(bytes) => Some(bytes),
+                HunkLine::InsertLine(_) => None,
+            })
+            .enumerate()
+            .map(|(i, bytes)| {
+                let pos = self.orig_start() + i;
+                (pos + 1, orig_lines.get(pos) == Some(&bytes.as_slice()))
+            })
+            .collect()
+    }
+
+    /// Apply just this hunk to a buffer of lines
+    ///
+    /// This is meant for applying a subset of the hunks of a patch, one at a time.
+    /// `cursor` is the index of the first line in `orig_lines` that has not been
+    /// consumed yet; the lines from `cursor` up to the start of the hunk are passed
+    /// through, followed by the lines produced by the hunk. Once all hunks are applied,
+    /// the caller appends the remaining lines, `orig_lines[cursor..]`.
+    ///
+    /// # Returns
+    /// The output lines, and the cursor to use for the next hunk
+    pub fn apply_at(
+        &self,
+        orig_lines: &[Vec<u8>],
+        cursor: usize,
+    ) -> Result<(Vec<Vec<u8>>, usize), crate::parse::PatchConflict> {
+        let borrowed = orig_lines.iter().map(|l| l.as_slice()).collect::<Vec<_>>();
+        let placement = self.place_exact(&borrowed, cursor)?;
+        let mut output = Vec::new();
+        let next = placement.apply(self, &borrowed, cursor, &mut output);
+        Ok((output.into_iter().map(|(_, line)| line.to_vec()).collect(), next))
+    }
+
+    /// Place this hunk exactly where its header says it goes
+    ///
+    /// `cursor` is the first line of `orig_lines` the hunk may touch.
+    ///
+    /// # Returns
+    /// The placement, or the first line that does not match
+    pub(crate) fn place_exact(
+        &self,
+        orig_lines: &[&[u8]],
+        cursor: usize,
+    ) -> Result<HunkPlacement, crate::parse::PatchConflict> {
+        let start = self.orig_start();
+        if start < cursor || start > orig_lines.len() {
+            return Err(crate::parse::PatchConflict {
+                line_no: start + 1,
+                orig_line: orig_lines.get(start).map(|l| l.to_vec()).unwrap_or_default(),
+                patch_line: Vec::new(),
+            });
+        }
+        if let Some(conflict) = self.find_conflict(orig_lines, start) {
+            return Err(conflict);
+        }
+        Ok(HunkPlacement {
+            match_start: start,
+            skip_leading: 0,
+            skip_trailing: 0,
+            offset: 0,
+            fuzz: 0,
+        })
+    }
+
+    /// Whether this hunk only adds lines
+    pub fn is_pure_addition(&self) -> bool {
+        !self.lines.iter().any(|line| matches!(line, HunkLine::RemoveLine(_)))
+            && self.lines.iter().any(|line| matches!(line, HunkLine::InsertLine(_)))
+    }
+
+    /// Whether this hunk only removes lines
+    pub fn is_pure_deletion(&self) -> bool {
+        !self.lines.iter().any(|line| matches!(line, HunkLine::InsertLine(_)))
+            && self.lines.iter().any(|line| matches!(line, HunkLine::RemoveLine(_)))
+    }
+
+    /// Whether this hunk both adds and removes lines
+    pub fn is_modification(&self) -> bool {
+        self.lines.iter().any(|line| matches!(line, HunkLine::InsertLine(_)))
+            && self.lines.iter().any(|line| matches!(line, HunkLine::RemoveLine(_)))
+    }
+
+    /// Check that the ranges in the header match the lines of this hunk
+    pub fn validate(&self) -> Result<(), HunkValidationError> {
+        let count = |f: fn(&HunkLine) -> bool| self.lines.iter().filter(|l| f(l)).count();
+        let actual_orig = count(|l| !matches!(l, HunkLine::InsertLine(_)));
+        let actual_mod = count(|l| !matches!(l, HunkLine::RemoveLine(_)));
+        if actual_orig == self.orig_range && actual_mod == self.mod_range {
+            return Ok(());
+        }
+        Err(HunkValidationError {
+            expected_orig: self.orig_range,
+            actual_orig,
+            expected_mod: self.mod_range,
+            actual_mod,
+        })
+    }
+
+    /// Create the hunk that undoes this one
+    ///
+    /// The original and modified ranges are swapped, and inserted lines become
+    /// removed lines and vice versa. The tail is kept.
+    pub fn reverse(&self) -> Hunk {
+        let mut hunk = Hunk::new(self.mod_pos, self.mod_range, self.orig_pos, self.orig_range, self.tail.clone());
+        hunk.lines = self
+            .lines
+            .iter()
+            .map(|line| match line {
+                HunkLine::ContextLine(bytes) => HunkLine::ContextLine(bytes.clone()),
+                HunkLine::InsertLine(bytes) => HunkLine::RemoveLine(bytes.clone()),
+                HunkLine::RemoveLine(bytes) => HunkLine::InsertLine(bytes.clone()),
+            })
+            .collect();
+        hunk
+    }
+
+    /// Iterate over the lines of this hunk along with their line numbers
+    ///
+    /// Yields the (1-based) line number in the original file (`None` for inserted
+    /// lines) and in the modified file (`None` for removed lines) for each line.
+    pub fn numbered_lines(
+        &self,
+    ) -> impl Iterator<Item = (Option<usize>, Option<usize>, &HunkLine)> + '_ {
+        let mut orig_line = self.orig_start() + 1;
+        let mut mod_line = self.mod_pos;
+        self.lines.iter().map(move |line| match line {
+            HunkLine::ContextLine(_) => {
+                orig_line += 1;
+                mod_line += 1;
+                (Some(orig_line - 1), Some(mod_line - 1), line)
+            }
+            HunkLine::RemoveLine(_) => {
+                orig_line += 1;
+                (Some(orig_line - 1), None, line)
+            }
+            HunkLine::InsertLine(_) => {
+                mod_line += 1;
+                (None, Some(mod_line - 1), line)
+            }
+        })
+    }
+
+    /// Set the text after the closing `@@` of the hunk header, e.g. the function name
+    ///
+    /// The tail may not contain newlines or `@@`, since those would corrupt the header.
+    pub fn set_tail(&mut self, tail: Option<Vec<u8>>) -> Result<(), InvalidTail> {
+        if let Some(tail) = &tail {
+            if tail.iter().any(|&c| c == b'\n' || c == b'\r')
+                || tail.windows(2).any(|w| w == b"@@")
+            {
+                return Err(InvalidTail(tail.clone()));
+            }
+        }
+        self.tail = tail;
+        Ok(())
+    }
+
     pub fn lines(&self) -> &[HunkLine] {
         &self.lines
     }
@@ -278,17 +2727,13 @@ impl Hunk {
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut lines = vec![self.get_header()];
         for line in &self.lines {
-            lines.push(match line {
-                HunkLine::ContextLine(bytes) => bytes.clone(),
-                HunkLine::InsertLine(bytes) => bytes.clone(),
-                HunkLine::RemoveLine(bytes) => bytes.clone(),
-            });
+            lines.push(line.as_bytes());
         }
         lines.concat()
     }
 
     pub fn shift_to_mod(&self, pos: usize) -> Option<isize> {
-        if pos < self.orig_pos - 1 {
+        if pos < self.orig_pos.saturating_sub(1) {
             Some(0)
         } else if pos > self.orig_pos + self.orig_range {
             Some((self.mod_range as isize) - (self.orig_range as isize))
@@ -298,7 +2743,7 @@ impl Hunk {
     }
 
     fn shift_to_mod_lines(&self, pos: usize) -> Option<isize> {
-        let mut position = self.orig_pos - 1;
+        let mut position = self.orig_pos.saturating_sub(1);
         let mut shift = 0;
         for line in &self.lines {
             match line {
@@ -323,7 +2768,7 @@ impl Hunk {
 
 #[cfg(test)]
 mod hunk_tests {
-    use super::Hunk;
+    use super::{Hunk, HunkLine};
 
     #[test]
     fn from_header_test() {
@@ -331,11 +2776,336 @@ mod hunk_tests {
         assert_eq!(hunk, Hunk::new(1, 1, 2, 1, None));
     }
 
+    #[test]
+    fn classify() {
+        let mut addition = Hunk::new(1, 1, 1, 2, None);
+        addition.lines.extend([
+            HunkLine::ContextLine(b"a\n".to_vec()),
+            HunkLine::InsertLine(b"b\n".to_vec()),
+        ]);
+        assert!(addition.is_pure_addition());
+        assert!(!addition.is_pure_deletion());
+        assert!(!addition.is_modification());
+
+        let mut deletion = Hunk::new(1, 2, 1, 1, None);
+        deletion.lines.extend([
+            HunkLine::ContextLine(b"a\n".to_vec()),
+            HunkLine::RemoveLine(b"b\n".to_vec()),
+        ]);
+        assert!(!deletion.is_pure_addition());
+        assert!(deletion.is_pure_deletion());
+        assert!(!deletion.is_modification());
+
+        let mut mixed = Hunk::new(1, 1, 1, 1, None);
+        mixed.lines.extend([
+            HunkLine::RemoveLine(b"a\n".to_vec()),
+            HunkLine::InsertLine(b"b\n".to_vec()),
+        ]);
+        assert!(!mixed.is_pure_addition());
+        assert!(!mixed.is_pure_deletion());
+        assert!(mixed.is_modification());
+    }
+
+    #[test]
+    fn apply_at_second_hunk_only() {
+        let orig = (1..=10).map(|i| format!("line {}\n", i).into_bytes()).collect::<Vec<_>>();
+        let mut first = Hunk::new(2, 1, 2, 1, None);
+        first.lines.push(HunkLine::RemoveLine(b"line 2\n".to_vec()));
+        first.lines.push(HunkLine::InsertLine(b"line two\n".to_vec()));
+        let mut second = Hunk::new(8, 2, 8, 3, None);
+        second.lines.extend([
+            HunkLine::ContextLine(b"line 8\n".to_vec()),
+            HunkLine::InsertLine(b"line 8.5\n".to_vec()),
+            HunkLine::ContextLine(b"line 9\n".to_vec()),
+        ]);
+
+        // Skip the first hunk, apply only the second
+        let (mut output, cursor) = second.apply_at(&orig, 0).unwrap();
+        assert_eq!(cursor, 9);
+        output.extend_from_slice(&orig[cursor..]);
+        let mut expected = orig.clone();
+        expected.insert(8, b"line 8.5\n".to_vec());
+        assert_eq!(output, expected);
+
+        // Hunks can't be applied out of order
+        let (_, cursor) = second.apply_at(&orig, 0).unwrap();
+        assert!(first.apply_at(&orig, cursor).is_err());
+    }
+
+    #[test]
+    fn numbered_lines() {
+        let mut hunk = Hunk::new(10, 3, 20, 3, None);
+        hunk.lines.extend([
+            HunkLine::ContextLine(b"a\n".to_vec()),
+            HunkLine::RemoveLine(b"b\n".to_vec()),
+            HunkLine::RemoveLine(b"c\n".to_vec()),
+            HunkLine::InsertLine(b"C\n".to_vec()),
+            HunkLine::InsertLine(b"D\n".to_vec()),
+        ]);
+        let numbers = hunk
+            .numbered_lines()
+            .map(|(orig, modi, _)| (orig, modi))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            numbers,
+            vec![
+                (Some(10), Some(20)),
+                (Some(11), None),
+                (Some(12), None),
+                (None, Some(21)),
+                (None, Some(22)),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_at_conflict() {
+        let orig = vec![b"a\n".to_vec(), b"b\n".to_vec()];
+        let mut hunk = Hunk::new(2, 1, 2, 1, None);
+        hunk.lines.push(HunkLine::RemoveLine(b"x\n".to_vec()));
+        let conflict = hunk.apply_at(&orig, 0).unwrap_err();
+        assert_eq!(conflict.line_no, 2);
+        assert_eq!(conflict.orig_line, b"b\n");
+    }
+
+    #[test]
+    fn set_tail() {
+        let mut hunk = Hunk::new(1, 1, 2, 1, None);
+        hunk.set_tail(Some(b"fn main()".to_vec())).unwrap();
+        assert_eq!(hunk.get_header(), b"@@ -1 +2 @@ fn main()\n");
+        assert_eq!(Hunk::from_header(&hunk.get_header()).unwrap(), hunk);
+
+        hunk.set_tail(None).unwrap();
+        assert_eq!(hunk.get_header(), b"@@ -1 +2 @@\n");
+    }
+
+    #[test]
+    fn set_tail_invalid() {
+        let mut hunk = Hunk::new(1, 1, 2, 1, None);
+        assert_eq!(
+            hunk.set_tail(Some(b"two\nlines".to_vec())),
+            Err(super::InvalidTail(b"two\nlines".to_vec()))
+        );
+        assert!(hunk.set_tail(Some(b"@@ -3 +4 @@".to_vec())).is_err());
+        assert_eq!(hunk.tail, None);
+    }
+
     #[test]
     fn from_header_tail() {
         let hunk = Hunk::from_header(&b"@@ -1 +2 @@ function()\n"[..]).unwrap();
         assert_eq!(hunk, Hunk::new(1, 1, 2, 1, Some(b"function()".to_vec())));
     }
+
+    #[test]
+    fn validate() {
+        let mut hunk = Hunk::new(1, 2, 1, 2, None);
+        hunk.lines.extend([
+            HunkLine::ContextLine(b"a\n".to_vec()),
+            HunkLine::RemoveLine(b"b\n".to_vec()),
+            HunkLine::InsertLine(b"B\n".to_vec()),
+        ]);
+        assert_eq!(hunk.validate(), Ok(()));
+
+        hunk.lines.push(HunkLine::InsertLine(b"C\n".to_vec()));
+        assert_eq!(
+            hunk.validate(),
+            Err(super::HunkValidationError {
+                expected_orig: 2,
+                actual_orig: 2,
+                expected_mod: 2,
+                actual_mod: 3,
+            })
+        );
+
+        let mut patch = super::UnifiedPatch::new(b"a".to_vec(), None, b"a".to_vec(), None);
+        patch.hunks.push(hunk);
+        let mut out = Vec::new();
+        let err = patch.write_checked(&mut out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn context_matches() {
+        let mut hunk = Hunk::new(2, 3, 2, 3, None);
+        hunk.lines.extend([
+            HunkLine::ContextLine(b"b\n".to_vec()),
+            HunkLine::RemoveLine(b"c\n".to_vec()),
+            HunkLine::InsertLine(b"C\n".to_vec()),
+            HunkLine::ContextLine(b"d\n".to_vec()),
+        ]);
+        assert_eq!(
+            hunk.context_matches(b"a\nb\nc\nx\n"),
+            vec![(2, true), (3, true), (4, false)]
+        );
+    }
+
+    #[test]
+    fn from_header_no_newline() {
+        let hunk = Hunk::from_header(&b"@@ -1 +1 @@"[..]).unwrap();
+        assert_eq!(hunk, Hunk::new(1, 1, 1, 1, None));
+        assert_eq!(hunk.as_bytes(), b"@@ -1 +1 @@\n");
+        let hunk = Hunk::from_header(&b"@@ -1 +1 @@ tail"[..]).unwrap();
+        assert_eq!(hunk.tail, Some(b"tail".to_vec()));
+
+        let data = b"--- a\n+++ a\n@@ -1 +1 @@\n-x\n+y\n@@ -3,0 +3,0 @@";
+        let patch = super::UnifiedPatch::parse_patch(crate::parse::splitlines(data), false).unwrap();
+        assert_eq!(patch.hunks.len(), 2);
+        assert_eq!(patch.as_bytes(), [&data[..], b"\n"].concat());
+
+        // A truncated hunk that should have had lines is an error, with or without
+        // the newline after its header
+        for data in [&b"--- a\n+++ a\n@@ -1 +1 @@"[..], b"--- a\n+++ a\n@@ -1 +1 @@\n"] {
+            assert_eq!(
+                super::UnifiedPatch::parse_patch(crate::parse::splitlines(data), false).unwrap_err(),
+                crate::parse::Error::PatchSyntax("Unexpected end of hunk", b"@@ -1 +1 @@\n".to_vec())
+            );
+            assert!(crate::parse::parse_patch(crate::parse::splitlines(data), true).is_err());
+        }
+    }
+
+    #[test]
+    fn from_header_no_space_before_closing() {
+        let hunk = Hunk::from_header(&b"@@ -1 +2@@\n"[..]).unwrap();
+        assert_eq!(hunk, Hunk::new(1, 1, 2, 1, None));
+        let hunk = Hunk::from_header(&b"@@ -1,2 +2,3@@ tail\n"[..]).unwrap();
+        assert_eq!(hunk, Hunk::new(1, 2, 2, 3, Some(b"tail".to_vec())));
+    }
+
+    #[test]
+    fn from_header_extra_spaces() {
+        let hunk = Hunk::from_header(&b"@@ -1,3 +1,3  @@\n"[..]).unwrap();
+        assert_eq!(hunk, Hunk::new(1, 3, 1, 3, None));
+        let hunk = Hunk::from_header(&b"@@  -1,3  +1,4 @@ tail\n"[..]).unwrap();
+        assert_eq!(hunk, Hunk::new(1, 3, 1, 4, Some(b"tail".to_vec())));
+    }
+}
+
+/// Builder for a `Hunk`, keeping track of its ranges
+#[derive(Debug, Clone, Default)]
+pub struct HunkBuilder {
+    lines: Vec<HunkLine>,
+}
+
+impl HunkBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a line that is the same in both files
+    pub fn context(mut self, line: &[u8]) -> Self {
+        self.lines.push(HunkLine::ContextLine(line.to_vec()));
+        self
+    }
+
+    /// Add a line that only exists in the modified file
+    pub fn insert(mut self, line: &[u8]) -> Self {
+        self.lines.push(HunkLine::InsertLine(line.to_vec()));
+        self
+    }
+
+    /// Add a line that only exists in the original file
+    pub fn remove(mut self, line: &[u8]) -> Self {
+        self.lines.push(HunkLine::RemoveLine(line.to_vec()));
+        self
+    }
+
+    /// Create the hunk, with ranges computed from its lines
+    ///
+    /// # Arguments
+    /// * `orig_pos` - Position of the hunk in the original file
+    /// * `mod_pos` - Position of the hunk in the modified file
+    /// * `tail` - Text to put after the hunk header, e.g. a function name
+    pub fn build(self, orig_pos: usize, mod_pos: usize, tail: Option<Vec<u8>>) -> Hunk {
+        let orig_range = self.lines.iter().filter(|l| !matches!(l, HunkLine::InsertLine(_))).count();
+        let mod_range = self.lines.iter().filter(|l| !matches!(l, HunkLine::RemoveLine(_))).count();
+        let mut hunk = Hunk::new(orig_pos, orig_range, mod_pos, mod_range, tail);
+        hunk.lines = self.lines;
+        hunk
+    }
+}
+
+/// Builder for a `UnifiedPatch`
+#[derive(Debug, Clone)]
+pub struct UnifiedPatchBuilder {
+    patch: UnifiedPatch,
+}
+
+impl UnifiedPatchBuilder {
+    pub fn new(
+        orig_name: &[u8],
+        orig_ts: Option<&[u8]>,
+        mod_name: &[u8],
+        mod_ts: Option<&[u8]>,
+    ) -> Self {
+        Self {
+            patch: UnifiedPatch::new(
+                orig_name.to_vec(),
+                orig_ts.map(|ts| ts.to_vec()),
+                mod_name.to_vec(),
+                mod_ts.map(|ts| ts.to_vec()),
+            ),
+        }
+    }
+
+    /// Add a hunk to the patch
+    pub fn hunk(mut self, hunk: Hunk) -> Self {
+        self.patch.hunks.push(hunk);
+        self
+    }
+
+    pub fn build(self) -> UnifiedPatch {
+        self.patch
+    }
+}
+
+#[cfg(test)]
+mod builder_tests {
+    use super::{Hunk, HunkBuilder, HunkLine, UnifiedPatch, UnifiedPatchBuilder};
+
+    #[test]
+    fn test_hunk_builder() {
+        let built = HunkBuilder::new()
+            .context(b"a\n")
+            .remove(b"b\n")
+            .insert(b"B\n")
+            .insert(b"C\n")
+            .context(b"d\n")
+            .build(3, 3, Some(b"fn main()".to_vec()));
+
+        let mut expected = Hunk::new(3, 3, 3, 4, Some(b"fn main()".to_vec()));
+        expected.lines.extend([
+            HunkLine::ContextLine(b"a\n".to_vec()),
+            HunkLine::RemoveLine(b"b\n".to_vec()),
+            HunkLine::InsertLine(b"B\n".to_vec()),
+            HunkLine::InsertLine(b"C\n".to_vec()),
+            HunkLine::ContextLine(b"d\n".to_vec()),
+        ]);
+        assert_eq!(built, expected);
+        assert_eq!(built.as_bytes(), expected.as_bytes());
+        assert_eq!(built.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_patch_builder() {
+        let hunk = HunkBuilder::new().remove(b"x\n").insert(b"y\n").build(1, 1, None);
+        let built = UnifiedPatchBuilder::new(b"a/foo", Some(b"2009-10-14 19:49:59 +0000"), b"b/foo", None)
+            .hunk(hunk.clone())
+            .build();
+
+        let mut expected = UnifiedPatch::new(
+            b"a/foo".to_vec(),
+            Some(b"2009-10-14 19:49:59 +0000".to_vec()),
+            b"b/foo".to_vec(),
+            None,
+        );
+        expected.hunks.push(hunk);
+        assert_eq!(built, expected);
+        assert_eq!(
+            built.as_bytes(),
+            b"--- a/foo\t2009-10-14 19:49:59 +0000\n+++ b/foo\n@@ -1 +1 @@\n-x\n+y\n"
+        );
+    }
 }
 
 /// Parse a patch range, handling the "1" special-case
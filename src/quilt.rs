@@ -1,9 +1,14 @@
 use std::collections::HashMap;
 use std::io::BufRead;
 
+use crate::patch::PatchStats;
+
 pub const DEFAULT_PATCHES_DIR: &str = "patches";
 pub const DEFAULT_SERIES_FILE: &str = "series";
 
+/// Alternative name for the series file, used by some older setups
+pub const ALTERNATE_SERIES_FILE: &str = "00list";
+
 /// Find the common prefix to use for patches
 ///
 /// # Arguments
@@ -66,24 +71,269 @@ mod find_common_patch_suffix_tests {
     }
 }
 
+/// Find the longest prefix shared by all patch names
+///
+/// Like `find_common_patch_suffix`, the series file and README files are ignored.
+///
+/// # Arguments
+/// * `names` - An iterator of patch names
+///
+/// # Returns
+/// The common prefix, or `None` if there are no patches or they have no prefix in
+/// common
+pub fn find_common_patch_prefix<'a>(names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let mut prefix: Option<&str> = None;
+    for name in names {
+        if name == "series" || name == "00list" || name.starts_with("README") {
+            continue;
+        }
+        prefix = Some(match prefix {
+            None => name,
+            Some(prefix) => {
+                let mut len = prefix
+                    .bytes()
+                    .zip(name.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                while !prefix.is_char_boundary(len) {
+                    len -= 1;
+                }
+                &prefix[..len]
+            }
+        });
+    }
+    prefix.filter(|prefix| !prefix.is_empty())
+}
+
+#[cfg(test)]
+mod find_common_patch_prefix_tests {
+    #[test]
+    fn test_find_common_patch_prefix() {
+        let names = vec!["series", "0001-foo.patch", "0002-bar.patch", "0010-baz.patch"];
+        assert_eq!(super::find_common_patch_prefix(names.into_iter()), Some("00"));
+    }
+
+    #[test]
+    fn test_find_common_patch_prefix_debian() {
+        let names = vec![
+            "README.source",
+            "debian/0001-foo.patch",
+            "debian/0002-bar.patch",
+            "debian/0003-baz.patch",
+        ];
+        assert_eq!(super::find_common_patch_prefix(names.into_iter()), Some("debian/000"));
+    }
+
+    #[test]
+    fn test_find_common_patch_prefix_none() {
+        assert_eq!(super::find_common_patch_prefix(vec!["foo.patch", "bar.patch"].into_iter()), None);
+        assert_eq!(super::find_common_patch_prefix(vec!["series"].into_iter()), None);
+    }
+}
+
 #[derive(Debug)]
 pub enum SeriesEntry {
     Patch {
         name: String,
         options: Vec<String>,
+
+        /// Guards that decide whether the patch is applied, see `Series::select`
+        guards: Vec<Guard>,
+
+        /// The line as read from the series file
+        ///
+        /// While it still has the same name, options and guards, `Series::write`
+        /// emits this line verbatim, so that reading and writing a series file is
+        /// byte-exact.
+        raw: Option<String>,
     },
+
+    /// A comment line exactly as written, including the `#` and any indentation
+    /// before it
+    ///
+    /// Use `SeriesEntry::comment` to create a comment that is written as `# text`.
     Comment(String),
+
+    /// An empty line
+    Blank,
+
+    /// A line that is not a valid entry, such as options without a patch name
+    ///
+    /// It is kept as written, so that writing the series reproduces it.
+    Invalid(String),
+}
+
+impl SeriesEntry {
+    /// Create a comment, written as `# text`
+    pub fn comment(text: &str) -> Self {
+        Self::Comment(format!("# {}", text))
+    }
+}
+
+/// The text of a comment line, following its `#`
+fn comment_text(comment: &str) -> &str {
+    let comment = comment.trim_start();
+    comment.strip_prefix('#').unwrap_or(comment)
+}
+
+/// A quilt guard on a patch, written as `#+name` or `#-name` after the patch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guard {
+    /// Whether this is a `#-` guard, which excludes the patch
+    pub negated: bool,
+
+    /// The guard symbol
+    pub name: String,
+}
+
+impl Guard {
+    /// Parse a guard token
+    ///
+    /// # Returns
+    /// The guard, or `None` if the token is not a guard
+    pub fn parse(token: &str) -> Option<Self> {
+        let (negated, name) = match token.strip_prefix("#+") {
+            Some(name) => (false, name),
+            None => (true, token.strip_prefix("#-")?),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        Some(Self { negated, name: name.to_string() })
+    }
+}
+
+impl std::fmt::Display for Guard {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#{}{}", if self.negated { '-' } else { '+' }, self.name)
+    }
+}
+
+/// Error computing the apply order of a series
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DependencyError {
+    /// A patch depends on a patch that is not in the series
+    Missing { patch: String, dependency: String },
+
+    /// The patches involved in (or depending on) a dependency cycle
+    Cycle(Vec<String>),
+}
+
+impl std::fmt::Display for DependencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Missing { patch, dependency } => {
+                write!(f, "{} depends on {}, which is not in the series", patch, dependency)
+            }
+            Self::Cycle(patches) => write!(f, "Dependency cycle among: {}", patches.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for DependencyError {}
+
+/// A problem found by `Series::validate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SeriesProblem {
+    /// A patch in the series has no file in the patches directory
+    MissingFile(String),
+
+    /// A patch is listed more than once in the series
+    Duplicate(String),
+
+    /// A file in the patches directory is not listed in the series
+    Unreferenced(String),
+}
+
+impl std::fmt::Display for SeriesProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::MissingFile(name) => write!(f, "{} is in the series but does not exist", name),
+            Self::Duplicate(name) => write!(f, "{} is listed more than once", name),
+            Self::Unreferenced(name) => write!(f, "{} is not in the series", name),
+        }
+    }
+}
+
+/// List the files under a directory, as paths relative to it
+///
+/// Hidden files and directories are skipped.
+fn list_patch_files(dir: &std::path::Path, prefix: &str, files: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = format!("{}{}", prefix, name);
+        if entry.file_type()?.is_dir() {
+            list_patch_files(&entry.path(), &format!("{}/", path), files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Parse the line for a patch in a series file into its name, options and guards
+///
+/// # Returns
+/// `None` if the line has no patch name
+fn parse_patch_line(line: &str) -> Option<(String, Vec<String>, Vec<Guard>)> {
+    let mut parts = split_series_line(line).into_iter();
+    let name = parts.next().filter(|name| !name.starts_with('-'))?;
+    let (guards, options): (Vec<_>, Vec<_>) = parts.partition(|part| Guard::parse(part).is_some());
+    let guards = guards.into_iter().filter_map(Guard::parse).collect();
+    let options = options.into_iter().map(|s| s.to_string()).collect();
+    Some((name.to_string(), options, guards))
+}
+
+/// Split a series file line into whitespace-separated words
+///
+/// Whitespace inside single or double quotes does not split words, so that option
+/// values such as `--msg="hello world"` are kept together. The quotes are kept, so
+/// that writing the words back out reproduces the line.
+fn split_series_line(line: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c.is_whitespace() => {
+                if let Some(s) = start.take() {
+                    words.push(&line[s..i]);
+                }
+            }
+            None => {
+                if start.is_none() {
+                    start = Some(i);
+                }
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+            }
+        }
+    }
+    if let Some(s) = start {
+        words.push(&line[s..]);
+    }
+    words
 }
 
 /// A quilt series file
 #[derive(Debug)]
 pub struct Series {
     pub entries: Vec<SeriesEntry>,
+
+    /// Problems found while reading the series file that did not prevent reading it
+    pub warnings: Vec<String>,
 }
 
 impl Series {
     pub fn new() -> Self {
-        Self { entries: vec![] }
+        Self { entries: vec![], warnings: vec![] }
     }
 
     pub fn len(&self) -> usize {
@@ -101,35 +351,231 @@ impl Series {
         })
     }
 
+    /// Find the index of the first entry (patch or comment) matching a predicate
+    pub fn entry_index(&self, predicate: impl Fn(&SeriesEntry) -> bool) -> Option<usize> {
+        self.entries.iter().position(predicate)
+    }
+
+    /// Find the index of the entry for a named patch
+    pub fn position_of_patch(&self, name: &str) -> Option<usize> {
+        self.entry_index(|entry| {
+            matches!(entry, SeriesEntry::Patch { name: entry_name, .. } if entry_name == name)
+        })
+    }
+
+    /// Find the index of the first comment with the given text
+    ///
+    /// Whitespace around the `#` and at the end of the text is ignored.
+    pub fn position_of_comment(&self, text: &str) -> Option<usize> {
+        self.entry_index(
+            |entry| matches!(entry, SeriesEntry::Comment(comment) if comment_text(comment).trim() == text),
+        )
+    }
+
+    /// Insert a comment immediately before a patch
+    ///
+    /// Returns `false` if the patch is not in the series.
+    pub fn insert_comment_before(&mut self, name: &str, comment: &str) -> bool {
+        match self.position_of_patch(name) {
+            Some(index) => {
+                self.entries.insert(index, SeriesEntry::comment(comment));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Insert a comment immediately after a patch
+    ///
+    /// Returns `false` if the patch is not in the series.
+    pub fn insert_comment_after(&mut self, name: &str, comment: &str) -> bool {
+        match self.position_of_patch(name) {
+            Some(index) => {
+                self.entries.insert(index + 1, SeriesEntry::comment(comment));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Find a patch by the file name component of its path
+    ///
+    /// This is useful when the caller only knows the file name of a patch, while the
+    /// series refers to it by a path relative to the patches directory.
+    pub fn find_patch_by_basename(&self, basename: &str) -> Option<&str> {
+        self.patches().find(|name| {
+            std::path::Path::new(name).file_name() == Some(std::ffi::OsStr::new(basename))
+        })
+    }
+
+    /// Options given for a patch in the series file
+    ///
+    /// Returns `None` if the patch is not in the series.
+    pub fn options_for(&self, name: &str) -> Option<&[String]> {
+        self.entries.iter().find_map(|entry| match entry {
+            SeriesEntry::Patch { name: entry_name, options, .. } if entry_name == name => {
+                Some(options.as_slice())
+            }
+            _ => None,
+        })
+    }
+
+    /// Options that apply to a patch, taking defaults into account
+    ///
+    /// A comment of the form `# options: -p1` sets the default options for the patches
+    /// that follow it; these are used for patches that do not specify any options
+    /// of their own.
+    ///
+    /// Returns `None` if the patch is not in the series.
+    pub fn effective_options(&self, name: &str) -> Option<Vec<String>> {
+        let mut defaults: Vec<String> = Vec::new();
+        for entry in &self.entries {
+            match entry {
+                SeriesEntry::Comment(comment) => {
+                    if let Some(options) = comment_text(comment).trim_start().strip_prefix("options:") {
+                        defaults = options.split_whitespace().map(|s| s.to_string()).collect();
+                    }
+                }
+                SeriesEntry::Patch { name: entry_name, options, .. } if entry_name == name => {
+                    return Some(if options.is_empty() { defaults } else { options.clone() });
+                }
+                SeriesEntry::Patch { .. } | SeriesEntry::Blank | SeriesEntry::Invalid(_) => {}
+            }
+        }
+        None
+    }
+
+    /// Dependencies declared for each patch, in series order
+    ///
+    /// Dependencies are declared with DEP-3 style `# Depends: a.patch b.patch` comments
+    /// preceding a patch; names may be separated by whitespace or commas.
+    fn dependencies(&self) -> Vec<(&str, Vec<&str>)> {
+        let mut ret = Vec::new();
+        let mut pending: Vec<&str> = Vec::new();
+        for entry in &self.entries {
+            match entry {
+                SeriesEntry::Comment(comment) => {
+                    if let Some(depends) = comment_text(comment).trim_start().strip_prefix("Depends:") {
+                        pending.extend(
+                            depends
+                                .split(|c: char| c == ',' || c.is_whitespace())
+                                .filter(|s| !s.is_empty()),
+                        );
+                    }
+                }
+                SeriesEntry::Patch { name, .. } => {
+                    ret.push((name.as_str(), std::mem::take(&mut pending)));
+                }
+                SeriesEntry::Blank | SeriesEntry::Invalid(_) => {}
+            }
+        }
+        ret
+    }
+
+    /// Compute an order in which to apply the patches that honours their dependencies
+    ///
+    /// Patches are kept in series order where the dependencies allow it.
+    ///
+    /// # Returns
+    /// The patch names in apply order, or an error if a dependency is not in the
+    /// series or the dependencies form a cycle
+    pub fn apply_order(&self) -> Result<Vec<String>, DependencyError> {
+        let dependencies = self.dependencies();
+        let index = |name: &str| dependencies.iter().position(|(n, _)| *n == name);
+        let mut remaining = dependencies
+            .iter()
+            .map(|(name, depends)| {
+                depends
+                    .iter()
+                    .map(|dep| {
+                        index(dep).ok_or_else(|| DependencyError::Missing {
+                            patch: name.to_string(),
+                            dependency: dep.to_string(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut done = vec![false; dependencies.len()];
+        let mut order = Vec::with_capacity(dependencies.len());
+        while order.len() < dependencies.len() {
+            let next = (0..dependencies.len())
+                .find(|&i| !done[i] && remaining[i].iter().all(|&dep| done[dep]));
+            match next {
+                Some(i) => {
+                    done[i] = true;
+                    remaining[i].clear();
+                    order.push(dependencies[i].0.to_string());
+                }
+                None => {
+                    return Err(DependencyError::Cycle(
+                        (0..dependencies.len())
+                            .filter(|&i| !done[i])
+                            .map(|i| dependencies[i].0.to_string())
+                            .collect(),
+                    ));
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    /// Read a series file
+    ///
+    /// Comments and blank lines are kept as they are, so that writing the series
+    /// back out reproduces a well-formed file byte for byte. Use `normalize` to
+    /// tidy them up.
     pub fn read<R: std::io::Read>(reader: R) -> std::io::Result<Self> {
         let mut series = Self::new();
 
         let reader = std::io::BufReader::new(reader);
 
-        for line in reader.lines() {
+        for (i, line) in reader.lines().enumerate() {
             let line = line?;
-            let line = line.trim();
+            let trimmed = line.trim();
 
-            if line.starts_with('#') {
-                series.entries.push(SeriesEntry::Comment(line.to_string()));
+            if trimmed.is_empty() {
+                series.entries.push(SeriesEntry::Blank);
                 continue;
             }
 
-            let mut parts = line.split_whitespace();
-            let name = parts.next().ok_or_else(|| {
-                std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "missing patch name in series file",
-                )
-            })?;
-            let options = parts.map(|s| s.to_string()).collect();
+            if trimmed.starts_with('#') {
+                series.entries.push(SeriesEntry::Comment(line));
+                continue;
+            }
 
-            series.entries.push(SeriesEntry::Patch { name: name.to_string(), options });
+            match parse_patch_line(trimmed) {
+                Some((name, options, guards)) => {
+                    series.entries.push(SeriesEntry::Patch { name, options, guards, raw: Some(line) });
+                }
+                None => {
+                    series
+                        .warnings
+                        .push(format!("line {}: options without a patch name: {}", i + 1, trimmed));
+                    series.entries.push(SeriesEntry::Invalid(line));
+                }
+            }
         }
 
         Ok(series)
     }
 
+    /// Drop blank lines, write all comments as `# text`, and write patches with
+    /// their name, options and guards separated by single spaces
+    pub fn normalize(&mut self) {
+        self.entries.retain(|entry| !matches!(entry, SeriesEntry::Blank));
+        for entry in &mut self.entries {
+            match entry {
+                SeriesEntry::Comment(comment) => {
+                    *entry = SeriesEntry::comment(comment_text(comment).trim());
+                }
+                SeriesEntry::Patch { raw, .. } => *raw = None,
+                SeriesEntry::Blank | SeriesEntry::Invalid(_) => {}
+            }
+        }
+    }
+
     /// Remove a patch from the series file
     pub fn remove(&mut self, name: &str) {
         self.entries.retain(|entry| match entry {
@@ -150,21 +596,143 @@ impl Series {
         self.entries.push(SeriesEntry::Patch {
             name: name.to_string(),
             options: options.map(|options| options.to_vec()).unwrap_or_default(),
+            guards: Vec::new(),
+            raw: None,
         });
     }
 
+    /// The patches that are applied when the given guards are active
+    ///
+    /// As with quilt's `guards` script, a patch is left out if any of its `#-`
+    /// guards is active, or if it has `#+` guards and none of them is active.
+    pub fn select(&self, active_guards: &std::collections::HashSet<String>) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match entry {
+                SeriesEntry::Patch { name, guards, .. } => {
+                    let active = |guard: &Guard| active_guards.contains(&guard.name);
+                    let excluded = guards.iter().any(|g| g.negated && active(g));
+                    let mut positive = guards.iter().filter(|g| !g.negated).peekable();
+                    let included = positive.peek().is_none() || positive.any(active);
+                    (included && !excluded).then_some(name.as_str())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Insert a patch so that it becomes the `index`-th patch of the series
+    ///
+    /// Only patches are counted, not comments. The patch is placed right after the
+    /// patch before it, so any comments preceding the patch that was at `index` stay
+    /// attached to that patch.
+    ///
+    /// # Panics
+    /// If `index` is greater than the number of patches
+    pub fn insert_at(&mut self, index: usize, name: &str, options: Option<&[String]>) {
+        self.insert_entry_at(
+            index,
+            SeriesEntry::Patch {
+                name: name.to_string(),
+                options: options.map(|options| options.to_vec()).unwrap_or_default(),
+                guards: Vec::new(),
+                raw: None,
+            },
+        );
+    }
+
+    fn insert_entry_at(&mut self, index: usize, entry: SeriesEntry) {
+        assert!(index <= self.len(), "index {} out of range for series of {} patches", index, self.len());
+        let position = match index.checked_sub(1) {
+            Some(prev) => {
+                self.entries
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, entry)| matches!(entry, SeriesEntry::Patch { .. }))
+                    .nth(prev)
+                    .map(|(i, _)| i + 1)
+                    .unwrap()
+            }
+            None => 0,
+        };
+        self.entries.insert(position, entry);
+    }
+
+    /// Move a patch so that it becomes the `new_index`-th patch of the series
+    ///
+    /// The patch keeps its options and guards; comments around it stay where they are. See
+    /// `insert_at` for where the patch ends up relative to comments.
+    ///
+    /// Returns `false` if the patch is not in the series.
+    ///
+    /// # Panics
+    /// If `new_index` is not less than the number of patches
+    pub fn move_patch(&mut self, name: &str, new_index: usize) -> bool {
+        let Some(position) = self.position_of_patch(name) else {
+            return false;
+        };
+        assert!(new_index < self.len(), "index {} out of range for series of {} patches", new_index, self.len());
+        let entry = self.entries.remove(position);
+        self.insert_entry_at(new_index, entry);
+        true
+    }
+
+    /// Check the series against the patches directory
+    ///
+    /// Reports patches without a file, patches listed more than once, and files in
+    /// `dir` that are not in the series. The series file itself, `00list` and
+    /// `README` files are not considered patches. If `dir` can not be read, only
+    /// the problems found in the series itself are reported.
+    pub fn validate(&self, dir: &std::path::Path) -> Vec<SeriesProblem> {
+        let mut problems = Vec::new();
+        let mut seen = std::collections::HashSet::new();
+        for name in self.patches() {
+            if !seen.insert(name) {
+                problems.push(SeriesProblem::Duplicate(name.to_string()));
+            } else if !dir.join(name).is_file() {
+                problems.push(SeriesProblem::MissingFile(name.to_string()));
+            }
+        }
+        let mut files = Vec::new();
+        if list_patch_files(dir, "", &mut files).is_ok() {
+            files.sort();
+            problems.extend(
+                files
+                    .into_iter()
+                    .filter(|name| {
+                        name != DEFAULT_SERIES_FILE && name != "00list" && !name.starts_with("README")
+                    })
+                    .filter(|name| !seen.contains(name.as_str()))
+                    .map(SeriesProblem::Unreferenced),
+            );
+        }
+        problems
+    }
+
     pub fn write<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
         for entry in &self.entries {
             match entry {
-                SeriesEntry::Patch { name, options } => {
+                SeriesEntry::Patch { name, options, guards, raw: Some(raw) }
+                    if parse_patch_line(raw.trim())
+                        .is_some_and(|parsed| parsed == (name.clone(), options.clone(), guards.clone())) =>
+                {
+                    writeln!(writer, "{}", raw)?;
+                }
+                SeriesEntry::Patch { name, options, guards, .. } => {
                     write!(writer, "{}", name)?;
                     for option in options {
                         write!(writer, " {}", option)?;
                     }
+                    for guard in guards {
+                        write!(writer, " {}", guard)?;
+                    }
                     writeln!(writer)?;
                 }
-                SeriesEntry::Comment(comment) => {
-                    writeln!(writer, "# {}", comment)?;
+                SeriesEntry::Comment(line) | SeriesEntry::Invalid(line) => {
+                    writeln!(writer, "{}", line)?;
+                }
+                SeriesEntry::Blank => {
+                    writeln!(writer)?;
                 }
             }
         }
@@ -179,6 +747,486 @@ impl Default for Series {
     }
 }
 
+#[cfg(test)]
+mod series_tests {
+    use super::{DependencyError, Guard, Series, SeriesEntry};
+
+    #[test]
+    fn test_insert_comment_before() {
+        let mut series = Series::read(&b"foo.patch\nbar.patch -p1\n"[..]).unwrap();
+        assert!(series.insert_comment_before("bar.patch", "needed for bar"));
+        assert_eq!(series.position_of_comment("needed for bar"), Some(1));
+        assert_eq!(series.position_of_patch("bar.patch"), Some(2));
+        assert!(!series.insert_comment_before("missing.patch", "nope"));
+
+        let mut out = Vec::new();
+        series.write(&mut out).unwrap();
+        assert_eq!(out, b"foo.patch\n# needed for bar\nbar.patch -p1\n");
+    }
+
+    #[test]
+    fn test_find_patch_by_basename() {
+        let series = Series::read(&b"subdir/foo.patch\nbar.patch\n"[..]).unwrap();
+        assert_eq!(series.find_patch_by_basename("foo.patch"), Some("subdir/foo.patch"));
+        assert_eq!(series.find_patch_by_basename("bar.patch"), Some("bar.patch"));
+        assert_eq!(series.find_patch_by_basename("subdir"), None);
+        assert_eq!(series.find_patch_by_basename("baz.patch"), None);
+    }
+
+    #[test]
+    fn test_options_for() {
+        let series = Series::read(&b"foo.patch -p1\nbar.patch\n"[..]).unwrap();
+        assert_eq!(series.options_for("foo.patch"), Some(&["-p1".to_string()][..]));
+        assert_eq!(series.options_for("bar.patch"), Some(&[][..]));
+        assert_eq!(series.options_for("missing.patch"), None);
+    }
+
+    #[test]
+    fn test_effective_options() {
+        let series = Series::read(
+            &b"# options: -p1 --fuzz=0\nfoo.patch\nbar.patch -p0\n"[..],
+        )
+        .unwrap();
+        assert_eq!(
+            series.effective_options("foo.patch"),
+            Some(vec!["-p1".to_string(), "--fuzz=0".to_string()])
+        );
+        assert_eq!(series.effective_options("bar.patch"), Some(vec!["-p0".to_string()]));
+        assert_eq!(series.effective_options("missing.patch"), None);
+    }
+
+    #[test]
+    fn test_insert_comment_after() {
+        let mut series = Series::read(&b"# header\nfoo.patch\nbar.patch\n"[..]).unwrap();
+        assert!(series.insert_comment_after("foo.patch", "after foo"));
+        assert_eq!(series.position_of_comment("header"), Some(0));
+        assert_eq!(series.position_of_comment("after foo"), Some(2));
+        assert_eq!(
+            series.entry_index(|entry| matches!(entry, SeriesEntry::Patch { .. })),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_quoted_options() {
+        let data = b"foo.patch -p1 --msg=\"hello world\" 'a b'\n";
+        let series = Series::read(&data[..]).unwrap();
+        assert_eq!(
+            series.options_for("foo.patch"),
+            Some(&["-p1".to_string(), "--msg=\"hello world\"".to_string(), "'a b'".to_string()][..])
+        );
+
+        let mut out = Vec::new();
+        series.write(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_options_only_line() {
+        let data = b"foo.patch -p1\n   -p1\nbar.patch\n";
+        let series = Series::read(&data[..]).unwrap();
+        assert_eq!(series.patches().collect::<Vec<_>>(), vec!["foo.patch", "bar.patch"]);
+        assert_eq!(series.warnings, vec!["line 2: options without a patch name: -p1"]);
+
+        let mut out = Vec::new();
+        series.write(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"# header\n\n#no space\nfoo.patch  -p1\n\n  #   indented  \n\tbar.patch #+foo -p0\n";
+        let mut series = Series::read(&data[..]).unwrap();
+        assert_eq!(series.len(), 2);
+        assert_eq!(series.position_of_comment("indented"), Some(5));
+
+        let mut out = Vec::new();
+        series.write(&mut out).unwrap();
+        assert_eq!(out, data);
+
+        series.normalize();
+        let mut out = Vec::new();
+        series.write(&mut out).unwrap();
+        assert_eq!(
+            out,
+            b"# header\n# no space\nfoo.patch -p1\n# indented\nbar.patch -p0 #+foo\n"
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_after_change() {
+        let mut series = Series::read(&b"foo.patch  -p1\n"[..]).unwrap();
+        if let SeriesEntry::Patch { options, .. } = &mut series.entries[0] {
+            options.push("-R".to_string());
+        }
+        series.entries.push(SeriesEntry::comment("trailer"));
+
+        let mut out = Vec::new();
+        series.write(&mut out).unwrap();
+        assert_eq!(out, b"foo.patch -p1 -R\n# trailer\n");
+    }
+
+    #[test]
+    fn test_guards() {
+        let data = b"base.patch\nfoo.patch -p1 #+foo\nnot-bar.patch #-bar\nboth.patch #+foo #+bar #-baz\n";
+        let series = Series::read(&data[..]).unwrap();
+        assert_eq!(series.options_for("foo.patch"), Some(&["-p1".to_string()][..]));
+        assert!(matches!(
+            &series.entries[2],
+            SeriesEntry::Patch { guards, .. }
+                if guards == &[Guard { negated: true, name: "bar".to_string() }]
+        ));
+
+        let active = |guards: &[&str]| guards.iter().map(|g| g.to_string()).collect();
+        assert_eq!(series.select(&active(&[])), vec!["base.patch", "not-bar.patch"]);
+        assert_eq!(
+            series.select(&active(&["foo"])),
+            vec!["base.patch", "foo.patch", "not-bar.patch", "both.patch"]
+        );
+        assert_eq!(series.select(&active(&["bar", "baz"])), vec!["base.patch"]);
+
+        let mut out = Vec::new();
+        series.write(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_insert_at() {
+        let mut series =
+            Series::read(&b"# header\nfoo.patch\n# about bar\nbar.patch\n# trailer\n"[..]).unwrap();
+        series.insert_at(0, "first.patch", None);
+        series.insert_at(2, "middle.patch", Some(&["-p1".to_string()]));
+        series.insert_at(4, "last.patch", None);
+
+        let mut out = Vec::new();
+        series.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "first.patch\n# header\nfoo.patch\nmiddle.patch -p1\n# about bar\nbar.patch\nlast.patch\n# trailer\n"
+        );
+    }
+
+    #[test]
+    fn test_move_patch() {
+        let mut series = Series::read(&b"a.patch\n# about b\nb.patch -p1\nc.patch\n"[..]).unwrap();
+        assert!(series.move_patch("b.patch", 2));
+        assert!(!series.move_patch("missing.patch", 0));
+        assert_eq!(series.patches().collect::<Vec<_>>(), vec!["a.patch", "c.patch", "b.patch"]);
+        assert_eq!(series.options_for("b.patch"), Some(&["-p1".to_string()][..]));
+        assert_eq!(series.position_of_comment("about b"), Some(1));
+
+        assert!(series.move_patch("b.patch", 0));
+        assert_eq!(series.patches().collect::<Vec<_>>(), vec!["b.patch", "a.patch", "c.patch"]);
+    }
+
+    #[test]
+    fn test_apply_order() {
+        let series = Series::read(
+            &b"# Depends: c.patch\na.patch\nb.patch\n# Depends: b.patch, d.patch\nc.patch\nd.patch\n"[..],
+        )
+        .unwrap();
+        assert_eq!(
+            series.apply_order().unwrap(),
+            vec!["b.patch", "d.patch", "c.patch", "a.patch"]
+        );
+    }
+
+    #[test]
+    fn test_apply_order_cycle() {
+        let series = Series::read(
+            &b"base.patch\n# Depends: b.patch\na.patch\n# Depends: a.patch\nb.patch\n"[..],
+        )
+        .unwrap();
+        assert_eq!(
+            series.apply_order(),
+            Err(DependencyError::Cycle(vec!["a.patch".to_string(), "b.patch".to_string()]))
+        );
+    }
+
+    #[test]
+    fn test_apply_order_missing() {
+        let series = Series::read(&b"# Depends: gone.patch\na.patch\n"[..]).unwrap();
+        assert_eq!(
+            series.apply_order(),
+            Err(DependencyError::Missing {
+                patch: "a.patch".to_string(),
+                dependency: "gone.patch".to_string(),
+            })
+        );
+    }
+}
+
+/// Count the lines inserted and removed by each patch in a quilt series
+///
+/// # Arguments
+/// * `dir` - The patches directory, containing the series file
+///
+/// # Returns
+/// The name and stats of each patch, in series order
+pub fn series_stats(dir: &std::path::Path) -> std::io::Result<Vec<(String, PatchStats)>> {
+    let series = Series::read(std::fs::File::open(dir.join(DEFAULT_SERIES_FILE))?)?;
+    series
+        .patches()
+        .map(|name| {
+            let data = std::fs::read(dir.join(name))?;
+            let mut stats = PatchStats::default();
+            for (_, patch) in crate::parse::parse_patches_spanned(&data) {
+                match patch {
+                    Ok(patch) => stats += patch.stats(),
+                    Err(crate::parse::Error::BinaryFiles(..)) => {}
+                    Err(e) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("{}: {}", name, e),
+                        ))
+                    }
+                }
+            }
+            Ok((name.to_string(), stats))
+        })
+        .collect()
+}
+
+/// A scratch directory for a test, removed again when it goes out of scope
+#[cfg(test)]
+struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("patchkit-{}-{}", name, std::process::id()));
+        // Left behind by an earlier run that was killed
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod validate_tests {
+    use super::{Series, SeriesProblem, TestDir};
+
+    #[test]
+    fn test_validate() {
+        let dir = TestDir::new("series-validate");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["series", "README", "a.patch", "sub/b.patch", "stray.patch"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let series = Series::read(&b"a.patch\nsub/b.patch\ngone.patch\na.patch\n"[..]).unwrap();
+
+        assert_eq!(
+            series.validate(&dir),
+            vec![
+                SeriesProblem::MissingFile("gone.patch".to_string()),
+                SeriesProblem::Duplicate("a.patch".to_string()),
+                SeriesProblem::Unreferenced("stray.patch".to_string()),
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod series_stats_tests {
+    use super::{series_stats, PatchStats, TestDir};
+
+    #[test]
+    fn test_series_stats() {
+        let dir = TestDir::new("series-stats");
+        std::fs::write(dir.join("series"), "small.patch\n# a comment\nbig.patch -p1\n").unwrap();
+        std::fs::write(dir.join("small.patch"), "--- a/foo\n+++ b/foo\n@@ -1 +1 @@\n-x\n+y\n").unwrap();
+        std::fs::write(
+            dir.join("big.patch"),
+            "--- a/foo\n+++ b/foo\n@@ -1,2 +1 @@\n-a\n-b\n+c\n--- a/bar\n+++ b/bar\n@@ -0,0 +1,2 @@\n+d\n+e\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            series_stats(&dir).unwrap(),
+            vec![
+                ("small.patch".to_string(), PatchStats { insertions: 1, deletions: 1, hunks: 1 }),
+                ("big.patch".to_string(), PatchStats { insertions: 3, deletions: 2, hunks: 2 }),
+            ]
+        );
+    }
+}
+
+/// Apply a patch, possibly touching several files, to a directory tree
+///
+/// All files are patched in memory first, so nothing is written unless the whole
+/// patch applies. Files created by the patch are created, files it deletes are
+/// removed, and renamed files are moved. If several sections of the patch touch the
+/// same file, each applies on top of the result of the previous one.
+///
+/// # Arguments
+/// * `data` - The patch, e.g. one of the patches in a quilt series
+/// * `root` - The directory to apply the patch in
+/// * `strip` - Number of leading path components to remove from the file names
+///   in the patch, as with `patch -p`
+///
+/// # Returns
+/// An error naming the offending file if the patch touches a binary file, names a
+/// file outside `root` (an absolute path or one containing `..`), or does not
+/// apply, or giving the byte offset of the section that can not be parsed
+pub fn apply_patch_to_dir(data: &[u8], root: &std::path::Path, strip: usize) -> std::io::Result<()> {
+    use crate::patch::{Patch, DEV_NULL};
+    let invalid = |name: &[u8], e: &dyn std::fmt::Display| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: {}", String::from_utf8_lossy(name), e),
+        )
+    };
+    let path = |name: &[u8]| -> std::io::Result<Option<std::path::PathBuf>> {
+        if name == DEV_NULL {
+            return Ok(None);
+        }
+        let stripped = crate::patch::strip_prefix(name, strip)
+            .ok_or_else(|| invalid(name, &"not enough path components to strip"))?;
+        let stripped = String::from_utf8_lossy(stripped);
+        let relative = std::path::Path::new(stripped.as_ref());
+        // Refuse names that would escape `root`
+        if !relative.components().all(|component| {
+            matches!(component, std::path::Component::Normal(_) | std::path::Component::CurDir)
+        }) {
+            return Err(invalid(name, &"path is absolute or contains '..'"));
+        }
+        Ok(Some(root.join(relative)))
+    };
+
+    // New contents for each file touched so far, or `None` if it is to be removed;
+    // later sections for the same file build on these rather than on the disk
+    let mut pending: HashMap<std::path::PathBuf, Option<Vec<u8>>> = HashMap::new();
+    for (span, patch) in crate::parse::parse_patches_spanned(data) {
+        let patch = match patch {
+            Ok(patch) => patch,
+            Err(crate::parse::Error::BinaryFiles(_, mod_name)) => {
+                return Err(invalid(&mod_name, &crate::patch::ApplyError::Unapplyable));
+            }
+            Err(e) => return Err(invalid(format!("patch at byte {}", span.start).as_bytes(), &e)),
+        };
+        let orig_path = path(&patch.orig_name)?;
+        let mod_path = path(&patch.mod_name)?;
+        let orig = match &orig_path {
+            Some(orig_path) => match pending.get(orig_path) {
+                Some(Some(contents)) => contents.clone(),
+                Some(None) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("{}: removed by an earlier section", String::from_utf8_lossy(&patch.orig_name)),
+                    ));
+                }
+                None => std::fs::read(orig_path)?,
+            },
+            None => Vec::new(),
+        };
+        let patched = patch.apply_exact(&orig).map_err(|e| invalid(&patch.mod_name, &e))?;
+        if let Some(orig_path) = orig_path {
+            if mod_path.as_ref() != Some(&orig_path) {
+                pending.insert(orig_path, None);
+            }
+        }
+        if let Some(mod_path) = mod_path {
+            pending.insert(mod_path, Some(patched));
+        }
+    }
+
+    for (path, contents) in pending {
+        let Some(contents) = contents else {
+            std::fs::remove_file(path)?;
+            continue;
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod apply_patch_to_dir_tests {
+    use super::{apply_patch_to_dir, TestDir};
+
+    #[test]
+    fn test_apply() {
+        let dir = TestDir::new("apply-dir");
+        std::fs::write(dir.join("foo"), "a\nb\n").unwrap();
+        std::fs::write(dir.join("gone"), "x\n").unwrap();
+        let patch = b"--- a/foo
++++ b/foo
+@@ -1,2 +1,2 @@
+ a
+-b
++B
+--- a/gone
++++ /dev/null
+@@ -1 +0,0 @@
+-x
+--- /dev/null
++++ b/sub/new
+@@ -0,0 +1 @@
++new
+";
+        apply_patch_to_dir(patch, &dir, 1).unwrap();
+        assert_eq!(std::fs::read(dir.join("foo")).unwrap(), b"a\nB\n");
+        assert!(!dir.join("gone").exists());
+        assert_eq!(std::fs::read(dir.join("sub/new")).unwrap(), b"new\n");
+
+        // A conflict in a later file leaves the earlier ones alone
+        let conflict = b"--- a/foo\n+++ b/foo\n@@ -1 +1 @@\n-a\n+A\n--- a/sub/new\n+++ b/sub/new\n@@ -1 +1 @@\n-old\n+x\n";
+        let err = apply_patch_to_dir(conflict, &dir, 1).unwrap_err();
+        assert!(err.to_string().starts_with("b/sub/new: Conflict"));
+        assert_eq!(std::fs::read(dir.join("foo")).unwrap(), b"a\nB\n");
+    }
+
+    #[test]
+    fn test_reject_escaping_paths() {
+        let dir = TestDir::new("apply-dir-escape");
+        let dotdot = b"--- /dev/null\n+++ b/../escaped\n@@ -0,0 +1 @@\n+x\n";
+        let dotdot_err = apply_patch_to_dir(dotdot, &dir, 1).unwrap_err();
+        assert_eq!(dotdot_err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(dotdot_err.to_string().starts_with("b/../escaped: "));
+        let absolute = b"--- /dev/null\n+++ /tmp/escaped\n@@ -0,0 +1 @@\n+x\n";
+        let absolute_err = apply_patch_to_dir(absolute, &dir, 0).unwrap_err();
+        assert!(absolute_err.to_string().starts_with("/tmp/escaped: "));
+        assert!(!dir.parent().unwrap().join("escaped").exists());
+    }
+
+    #[test]
+    fn test_same_file_twice() {
+        let dir = TestDir::new("apply-dir-twice");
+        std::fs::write(dir.join("foo"), "a\nb\nc\n").unwrap();
+        let patch = b"--- a/foo\n+++ b/foo\n@@ -1 +1 @@\n-a\n+A\n--- a/foo\n+++ b/foo\n@@ -3 +3 @@\n-c\n+C\n";
+        apply_patch_to_dir(patch, &dir, 1).unwrap();
+        assert_eq!(std::fs::read(dir.join("foo")).unwrap(), b"A\nb\nC\n");
+    }
+
+    #[test]
+    fn test_parse_error_offset() {
+        let dir = TestDir::new("apply-dir-parse-error");
+        std::fs::write(dir.join("foo"), "a\n").unwrap();
+        let patch = b"--- a/foo\n+++ b/foo\n@@ -1 +1 @@\n-a\n+A\n--- a/bar\n+++ b/bar\n@@ -1 +1 @@\n-a\n?A\n";
+        let err = apply_patch_to_dir(patch, &dir, 1).unwrap_err();
+        assert!(err.to_string().starts_with("patch at byte 38: "), "{}", err);
+    }
+}
+
 /// Read a .pc/.quilt_patches file
 pub fn read_quilt_patches<R: std::io::Read>(mut reader: R) -> std::path::PathBuf {
     let mut p = String::new();
@@ -192,3 +1240,69 @@ pub fn read_quilt_series<R: std::io::Read>(mut reader: R) -> std::path::PathBuf
    reader.read_to_string(&mut s).unwrap();
    s.into()
 }
+
+/// Find the patches directory and series file of a quilt tree
+///
+/// The patches directory is the one named in `.pc/.quilt_patches` if that exists,
+/// and `patches` otherwise. The series file is looked for in the patches
+/// directory, in this order:
+/// 1. the file named in `.pc/.quilt_series`, if that exists
+/// 2. `series`
+/// 3. `00list`
+///
+/// # Returns
+/// The patches directory and the path of the series file, or `None` if there is
+/// no series file
+pub fn find_series_file(root: &std::path::Path) -> Option<(std::path::PathBuf, std::path::PathBuf)> {
+    // The .pc files end with a newline
+    let read_pointer = |name: &str, read: fn(std::fs::File) -> std::path::PathBuf| {
+        let file = std::fs::File::open(root.join(".pc").join(name)).ok()?;
+        Some(std::path::PathBuf::from(read(file).to_string_lossy().trim_end()))
+    };
+    let patches_dir = root.join(
+        read_pointer(".quilt_patches", read_quilt_patches)
+            .unwrap_or_else(|| DEFAULT_PATCHES_DIR.into()),
+    );
+    let candidates = read_pointer(".quilt_series", read_quilt_series)
+        .into_iter()
+        .chain([DEFAULT_SERIES_FILE.into(), ALTERNATE_SERIES_FILE.into()]);
+    for candidate in candidates {
+        let path = patches_dir.join(candidate);
+        if path.is_file() {
+            return Some((patches_dir, path));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod find_series_file_tests {
+    use super::{find_series_file, TestDir};
+
+    #[test]
+    fn test_find_series_file() {
+        let root = TestDir::new("find-series");
+        std::fs::create_dir_all(root.join("patches")).unwrap();
+        std::fs::create_dir_all(root.join("debian/patches")).unwrap();
+        std::fs::create_dir_all(root.join(".pc")).unwrap();
+        assert_eq!(find_series_file(&root), None);
+        std::fs::write(root.join("patches/00list"), "").unwrap();
+        assert_eq!(
+            find_series_file(&root),
+            Some((root.join("patches"), root.join("patches/00list")))
+        );
+        std::fs::write(root.join("patches/series"), "").unwrap();
+        assert_eq!(
+            find_series_file(&root),
+            Some((root.join("patches"), root.join("patches/series")))
+        );
+        std::fs::write(root.join(".pc/.quilt_patches"), "debian/patches\n").unwrap();
+        std::fs::write(root.join(".pc/.quilt_series"), "custom\n").unwrap();
+        std::fs::write(root.join("debian/patches/series"), "").unwrap();
+        std::fs::write(root.join("debian/patches/custom"), "").unwrap();
+        assert_eq!(
+            find_series_file(&root),
+            Some((root.join("debian/patches"), root.join("debian/patches/custom")))
+        );
+    }
+}
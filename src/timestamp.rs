@@ -28,7 +28,7 @@ pub fn format_patch_date(secs: i64, mut offset: i64) -> Result<String, FormatPat
         return Err(FormatPatchDateError::NegativeTime(secs, offset));
     }
 
-    let dt = chrono::DateTime::<chrono::Utc>::from_utc(chrono::NaiveDateTime::from_timestamp(secs, 0), chrono::Utc);
+    let dt = chrono::DateTime::<chrono::Utc>::from_timestamp(secs, 0).unwrap();
 
     let sign = if offset >= 0 { '+' } else { '-' };
     let hours = offset.abs() / 3600;
@@ -85,6 +85,125 @@ pub fn parse_patch_date(date_str: &str) -> Result<(i64, i64), ParsePatchDateErro
     Ok((dt.timestamp(), offset))
 }
 
+/// Format a timestamp for a patch header, the way GNU diff does
+///
+/// This is `%Y-%m-%d %H:%M:%S.%f %z`, with six digits of fractional seconds,
+/// e.g. `2009-10-14 19:49:59.000000 +0000`.
+pub fn format_patch_timestamp(dt: chrono::DateTime<chrono::FixedOffset>) -> Vec<u8> {
+    dt.format("%Y-%m-%d %H:%M:%S%.6f %z").to_string().into_bytes()
+}
+
+/// A timestamp from a patch header, split into its parts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedTimestamp {
+    /// Date and time, as written (i.e. in the local time of the timestamp)
+    pub datetime: chrono::NaiveDateTime,
+
+    /// Offset from UTC, if it was given numerically or as a well-known zone name
+    pub offset: Option<chrono::FixedOffset>,
+
+    /// Zone name, if the timestamp had one (e.g. `PST`)
+    pub zone: Option<String>,
+}
+
+lazy_static! {
+    static ref RE_ISO_TIMESTAMP: regex::Regex = regex::Regex::new(
+        r"^(\d+-\d+-\d+)\s+(\d+:\d+:\d+(?:\.\d+)?)(?:\s+(?:([+-]\d{4})|([A-Za-z]+)))?$"
+    )
+    .unwrap();
+    static ref RE_CTIME_TIMESTAMP: regex::Regex = regex::Regex::new(
+        r"^[A-Za-z]{3}\s+([A-Za-z]{3})\s+(\d+)\s+(\d+:\d+:\d+(?:\.\d+)?)(?:\s+(?:([+-]\d{4})|([A-Za-z]+)))?\s+(\d{4})$"
+    )
+    .unwrap();
+}
+
+/// Offset of a zone name, for the names that are unambiguous
+fn zone_offset(zone: &str) -> Option<chrono::FixedOffset> {
+    let hours = match zone {
+        "UTC" | "UT" | "GMT" | "Z" => 0,
+        "EST" => -5,
+        "EDT" => -4,
+        "CDT" => -5,
+        "MST" => -7,
+        "MDT" => -6,
+        "PST" => -8,
+        "PDT" => -7,
+        "CET" => 1,
+        "CEST" => 2,
+        "JST" => 9,
+        _ => return None,
+    };
+    chrono::FixedOffset::east_opt(hours * 3600)
+}
+
+/// Parse a numeric offset like `+0530`
+fn numeric_offset(offset: &str) -> Option<chrono::FixedOffset> {
+    let hours = offset[1..3].parse::<i32>().ok()?;
+    let minutes = offset[3..5].parse::<i32>().ok()?;
+    if hours >= 24 || minutes >= 60 {
+        return None;
+    }
+    let secs = hours * 3600 + minutes * 60;
+    chrono::FixedOffset::east_opt(if offset.starts_with('-') { -secs } else { secs })
+}
+
+/// Parse a timestamp from a patch header
+///
+/// Both the `2024-01-01 12:34:56.789012 +0000` form written by `diff -u` and the
+/// `Mon Jan  1 12:34:56 PST 2024` form written by `diff -c` are accepted, with
+/// optional fractional seconds and an optional numeric offset or zone name.
+/// Zone names are only converted to an offset if they are unambiguous; the name
+/// is kept either way.
+pub fn parse(ts: &[u8]) -> Result<ParsedTimestamp, ParsePatchDateError> {
+    let date_str = String::from_utf8_lossy(ts);
+    let date_str = date_str.trim();
+    let invalid = || ParsePatchDateError::InvalidDate(date_str.to_string());
+    let (datetime, offset, zone) = if let Some(m) = RE_ISO_TIMESTAMP.captures(date_str) {
+        let datetime = chrono::NaiveDateTime::parse_from_str(
+            &format!("{} {}", &m[1], &m[2]),
+            "%Y-%m-%d %H:%M:%S%.f",
+        )
+        .map_err(|_| invalid())?;
+        (datetime, m.get(3), m.get(4))
+    } else if let Some(m) = RE_CTIME_TIMESTAMP.captures(date_str) {
+        let datetime = chrono::NaiveDateTime::parse_from_str(
+            &format!("{} {} {} {}", &m[1], &m[2], &m[6], &m[3]),
+            "%b %d %Y %H:%M:%S%.f",
+        )
+        .map_err(|_| invalid())?;
+        (datetime, m.get(4), m.get(5))
+    } else {
+        return Err(invalid());
+    };
+    let offset = match offset {
+        Some(offset) => Some(numeric_offset(offset.as_str()).ok_or_else(|| {
+            ParsePatchDateError::InvalidTimezoneOffset(date_str.to_string())
+        })?),
+        None => zone.and_then(|zone| zone_offset(zone.as_str())),
+    };
+    Ok(ParsedTimestamp { datetime, offset, zone: zone.map(|zone| zone.as_str().to_string()) })
+}
+
+/// Parse the timestamp from a patch header into an instant
+///
+/// See `parse` for the accepted forms. A timestamp without any timezone (as
+/// written by `diff -c`) is taken to be in UTC; one with an unknown zone name is
+/// reported as `ParsePatchDateError::InvalidTimezoneOffset`.
+pub fn parse_patch_timestamp(
+    ts: &[u8],
+) -> Result<chrono::DateTime<chrono::FixedOffset>, ParsePatchDateError> {
+    use chrono::TimeZone;
+    let parsed = parse(ts)?;
+    match (parsed.offset, parsed.zone) {
+        (Some(offset), _) => offset
+            .from_local_datetime(&parsed.datetime)
+            .single()
+            .ok_or_else(|| ParsePatchDateError::InvalidDate(String::from_utf8_lossy(ts).to_string())),
+        (None, Some(zone)) => Err(ParsePatchDateError::InvalidTimezoneOffset(zone)),
+        (None, None) => Ok(parsed.datetime.and_utc().fixed_offset()),
+    }
+}
+
 #[cfg(test)]
 mod test {
     #[test]
@@ -98,4 +217,55 @@ mod test {
             e => panic!("Expected MissingTimezoneOffset error, got {:?}", e),
         }
     }
+
+    #[test]
+    fn test_parse_patch_timestamp() {
+        let dt = super::parse_patch_timestamp(b"2009-10-14 19:49:59 +0200").unwrap();
+        assert_eq!(dt.timestamp(), 1255542599);
+        assert_eq!(dt.offset().local_minus_utc(), 7200);
+        let dt = super::parse_patch_timestamp(b"Thu Jan  1 00:00:00 1970").unwrap();
+        assert_eq!(dt.timestamp(), 0);
+        assert_eq!(
+            super::parse_patch_timestamp(b"Mon Jul 1 23:50:29 2024").unwrap().timestamp(),
+            1719877829
+        );
+        assert!(matches!(
+            super::parse_patch_timestamp(b"(working copy)"),
+            Err(super::ParsePatchDateError::InvalidDate(_))
+        ));
+    }
+
+    #[test]
+    fn test_format_patch_timestamp() {
+        let dt = chrono::DateTime::parse_from_rfc3339("2009-10-14T19:49:59.5+02:00").unwrap();
+        let formatted = super::format_patch_timestamp(dt);
+        assert_eq!(formatted, b"2009-10-14 19:49:59.500000 +0200");
+        assert_eq!(super::parse_patch_timestamp(&formatted).unwrap(), dt);
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = super::parse(b"2024-01-01 12:34:56.789012 +0000").unwrap();
+        assert_eq!(parsed.datetime.to_string(), "2024-01-01 12:34:56.789012");
+        assert_eq!(parsed.offset, chrono::FixedOffset::east_opt(0));
+        assert_eq!(parsed.zone, None);
+
+        let parsed = super::parse(b"Mon Jan 01 12:34:56 PST 2024").unwrap();
+        assert_eq!(parsed.datetime.to_string(), "2024-01-01 12:34:56");
+        assert_eq!(parsed.offset, chrono::FixedOffset::west_opt(8 * 3600));
+        assert_eq!(parsed.zone.as_deref(), Some("PST"));
+
+        // IST could be Indian, Irish or Israeli time
+        let parsed = super::parse(b"2024-01-01 12:34:56 IST").unwrap();
+        assert_eq!(parsed.offset, None);
+        assert_eq!(parsed.zone.as_deref(), Some("IST"));
+        assert!(matches!(
+            super::parse_patch_timestamp(b"2024-01-01 12:34:56 IST"),
+            Err(super::ParsePatchDateError::InvalidTimezoneOffset(_))
+        ));
+        assert_eq!(
+            super::parse_patch_timestamp(b"Mon Jan 01 12:34:56 PST 2024").unwrap().timestamp(),
+            1704141296
+        );
+    }
 }